@@ -0,0 +1,320 @@
+use std::ops::RangeInclusive;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use protobuf_support::lexer::loc::Loc;
+
+use crate::model::{
+    EnumValue, Enumeration, Field, FieldOrOneOf, FieldType, FileDescriptor, Group, Message, Rule,
+    WithLoc,
+};
+use crate::protobuf_abs_path::ProtobufAbsPath;
+use crate::protobuf_path::ProtobufPath;
+use crate::{Syntax, MAX_FIELD_NUMBER};
+
+/// Nesting depth of generated messages
+const MAX_DEPTH: u32 = 2;
+
+impl<'a> Arbitrary<'a> for Syntax {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(*u.choose(&[Syntax::Proto2, Syntax::Proto3])?)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Rule {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(*u.choose(&Rule::ALL)?)
+    }
+}
+
+/// Scalar, map, group or reference to a message or enum, which is not declared
+impl<'a> Arbitrary<'a> for FieldType {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        field_type(u, Syntax::Proto2, 0, &mut 0, true)
+    }
+}
+
+/// File which formats to valid `.proto` source: messages with fields of any type,
+/// nested messages and enums. Names are valid and unique, field numbers are in range.
+/// Referenced types are not declared.
+impl<'a> Arbitrary<'a> for FileDescriptor {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let syntax = Syntax::arbitrary(u)?;
+
+        let mut package = String::new();
+        for i in 0..u.int_in_range(0..=3)? {
+            // Index suffix avoids words lexed as numbers, e. g. `inf`
+            package.push_str(&format!(".{}{}", letters(u, b'a'..=b'z')?, i));
+        }
+
+        Ok(FileDescriptor {
+            package: ProtobufAbsPath::new(package),
+            syntax,
+            messages: messages(u, syntax, 0)?,
+            enums: enums(u, syntax)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Non-empty sequence of chars from `first` followed by lowercase letters
+fn letters(u: &mut Unstructured, first: RangeInclusive<u8>) -> Result<String> {
+    let mut r = String::new();
+    r.push(u.int_in_range(first)? as char);
+    for _ in 0..u.int_in_range(0..=5)? {
+        r.push(u.int_in_range(b'a'..=b'z')? as char);
+    }
+    Ok(r)
+}
+
+fn with_loc<T>(t: T) -> WithLoc<T> {
+    WithLoc {
+        loc: Loc::start(),
+        t,
+    }
+}
+
+// Names are made unique with an index, which is followed by a letter
+// or preceded by `_`, so it cannot merge with the random part.
+fn messages(u: &mut Unstructured, syntax: Syntax, depth: u32) -> Result<Vec<WithLoc<Message>>> {
+    if depth >= MAX_DEPTH {
+        return Ok(Vec::new());
+    }
+    let mut r = Vec::new();
+    for i in 0..u.int_in_range(0..=3)? {
+        let fields = fields(u, syntax, &mut 0, true)?
+            .into_iter()
+            .map(|f| with_loc(FieldOrOneOf::Field(f)))
+            .collect();
+        r.push(with_loc(Message {
+            name: format!("M{}{}", i, letters(u, b'a'..=b'z')?),
+            fields,
+            messages: messages(u, syntax, depth + 1)?,
+            enums: enums(u, syntax)?,
+            ..Default::default()
+        }));
+    }
+    Ok(r)
+}
+
+/// Fields numbered after `number`, which is updated to the greatest number
+/// of the fields and of fields of their groups
+fn fields(
+    u: &mut Unstructured,
+    syntax: Syntax,
+    number: &mut i32,
+    groups: bool,
+) -> Result<Vec<WithLoc<Field>>> {
+    let mut r = Vec::new();
+    for j in 0..u.int_in_range(0..=8)? {
+        *number += u.int_in_range(1..=1000)?;
+        // Reserved for the protobuf implementation
+        if (19000..=19999).contains(number) {
+            *number = 20000;
+        }
+        if *number > MAX_FIELD_NUMBER {
+            break;
+        }
+        r.push(with_loc(field(u, syntax, j, number, groups)?));
+    }
+    Ok(r)
+}
+
+fn field(
+    u: &mut Unstructured,
+    syntax: Syntax,
+    index: u32,
+    number: &mut i32,
+    groups: bool,
+) -> Result<Field> {
+    let field_number = *number;
+    let typ = field_type(u, syntax, index, number, groups)?;
+    let (name, rule) = match &typ {
+        // Map fields have no label
+        FieldType::Map(..) => (format!("{}_{}", letters(u, b'a'..=b'z')?, index), None),
+        FieldType::Group(group) => (group.name.to_ascii_lowercase(), Some(Rule::arbitrary(u)?)),
+        _ => {
+            let rule = match syntax {
+                Syntax::Proto2 => Some(Rule::arbitrary(u)?),
+                Syntax::Proto3 => *u.choose(&[None, Some(Rule::Optional), Some(Rule::Repeated)])?,
+            };
+            (format!("{}_{}", letters(u, b'a'..=b'z')?, index), rule)
+        }
+    };
+    Ok(Field {
+        name,
+        rule,
+        typ,
+        number: field_number,
+        options: Vec::new(),
+    })
+}
+
+/// Type of field `index` numbered `number`, fields of a group are numbered after it.
+///
+/// Groups are generated if `groups` and `syntax` is proto2, groups are not nested.
+fn field_type(
+    u: &mut Unstructured,
+    syntax: Syntax,
+    index: u32,
+    number: &mut i32,
+    groups: bool,
+) -> Result<FieldType> {
+    let kinds = if groups && syntax == Syntax::Proto2 {
+        4
+    } else {
+        3
+    };
+    Ok(match u.choose_index(kinds)? {
+        0 => scalar(u)?,
+        1 => message_or_enum(u)?,
+        2 => {
+            let key = u
+                .choose(&[
+                    FieldType::Int32,
+                    FieldType::Int64,
+                    FieldType::Uint32,
+                    FieldType::Uint64,
+                    FieldType::Sint32,
+                    FieldType::Sint64,
+                    FieldType::Bool,
+                    FieldType::Fixed64,
+                    FieldType::Sfixed64,
+                    FieldType::String,
+                    FieldType::Fixed32,
+                    FieldType::Sfixed32,
+                ])?
+                .clone();
+            let value = if u.arbitrary()? {
+                scalar(u)?
+            } else {
+                message_or_enum(u)?
+            };
+            FieldType::Map(Box::new((key, value)))
+        }
+        _ => FieldType::Group(Group {
+            // Group name starts with an uppercase letter
+            name: format!("G{}{}", index, letters(u, b'a'..=b'z')?),
+            fields: fields(u, syntax, number, false)?,
+        }),
+    })
+}
+
+fn scalar(u: &mut Unstructured) -> Result<FieldType> {
+    Ok(u.choose(&[
+        FieldType::Int32,
+        FieldType::Int64,
+        FieldType::Uint32,
+        FieldType::Uint64,
+        FieldType::Sint32,
+        FieldType::Sint64,
+        FieldType::Bool,
+        FieldType::Fixed64,
+        FieldType::Sfixed64,
+        FieldType::Double,
+        FieldType::String,
+        FieldType::Bytes,
+        FieldType::Fixed32,
+        FieldType::Sfixed32,
+        FieldType::Float,
+    ])?
+    .clone())
+}
+
+/// Relative or absolute path of capitalized components, which are not keywords
+fn message_or_enum(u: &mut Unstructured) -> Result<FieldType> {
+    let mut path = String::new();
+    for i in 0..u.int_in_range(1..=3)? {
+        if i != 0 || u.arbitrary()? {
+            path.push('.');
+        }
+        path.push_str(&format!("{}{}", letters(u, b'A'..=b'Z')?, i));
+    }
+    Ok(FieldType::MessageOrEnum(ProtobufPath::new(path)))
+}
+
+fn enums(u: &mut Unstructured, syntax: Syntax) -> Result<Vec<WithLoc<Enumeration>>> {
+    let mut r = Vec::new();
+    for i in 0..u.int_in_range(0..=2)? {
+        let name = format!("E{}{}", i, letters(u, b'a'..=b'z')?);
+        // Enum values are scoped in the enclosing scope, prefix them with enum name
+        let prefix = name.to_uppercase();
+        let mut values = Vec::new();
+        // First value of proto3 enum must be zero
+        let mut number = match syntax {
+            Syntax::Proto2 => u.int_in_range(-1000..=1000)?,
+            Syntax::Proto3 => 0,
+        };
+        for j in 0..u.int_in_range(1..=5)? {
+            if j != 0 {
+                number += u.int_in_range(1..=1000)?;
+            }
+            values.push(EnumValue {
+                name: format!(
+                    "{}_{}_{}",
+                    prefix,
+                    letters(u, b'A'..=b'Z')?.to_uppercase(),
+                    j
+                ),
+                number,
+                options: Vec::new(),
+                loc: Loc::start(),
+            });
+        }
+        r.push(with_loc(Enumeration {
+            name,
+            values,
+            options: Vec::new(),
+            reserved_nums: Vec::new(),
+            reserved_names: Vec::new(),
+        }));
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Pseudo-random bytes, so failures are reproducible
+    fn data(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_format_parse_round_trip() {
+        for seed in 1..=300 {
+            let data = data(seed, 4096);
+            let file = FileDescriptor::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let formatted = file.to_string();
+            let parsed = FileDescriptor::parse(&formatted)
+                .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, formatted));
+            assert_eq!(formatted, parsed.to_string(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_field_types() {
+        let mut kinds = [false; 4];
+        for seed in 1..=100 {
+            let data = data(seed, 1024);
+            let typ = FieldType::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let kind = match typ {
+                FieldType::MessageOrEnum(..) => 0,
+                FieldType::Map(..) => 1,
+                FieldType::Group(..) => 2,
+                _ => 3,
+            };
+            kinds[kind] = true;
+        }
+        assert_eq!([true; 4], kinds);
+    }
+}
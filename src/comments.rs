@@ -0,0 +1,385 @@
+use protobuf_support::lexer::{
+    lexer_impl::Lexer, loc::Loc, parser_language::ParserLanguage, token::Token,
+};
+
+use crate::span::token_end;
+
+/// Comments of a declaration, following the conventions of
+/// `SourceCodeInfo.Location` in `descriptor.proto`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comments {
+    /// Comment block directly before the declaration
+    pub leading: Option<String>,
+    /// Comment after `;` ending the declaration or after `{` opening
+    /// its body, on the same line, or on the next line when followed
+    /// by a blank line
+    pub trailing: Option<String>,
+    /// Comment blocks before the declaration separated from it
+    /// by a blank line, in source order
+    pub detached: Vec<String>,
+}
+
+/// Consecutive `//` comments or a single `/* */` comment
+struct CommentBlock {
+    /// `//` comments are merged with `//` comments on the next line
+    line_comments: bool,
+    start_line: u32,
+    end_line: u32,
+    text: String,
+}
+
+struct TokenRange {
+    token: Token,
+    loc: Loc,
+    start: usize,
+    end: usize,
+    end_line: u32,
+}
+
+/// Index of tokens of a source to find comments of declarations.
+pub struct SourceComments<'a> {
+    source: &'a str,
+    tokens: Vec<TokenRange>,
+}
+
+/// Parse comments separated by whitespace, `line` is the line of `gap` start.
+///
+/// If `after_token`, a comment on the first line is not merged with
+/// comments on the following lines.
+fn comment_blocks(gap: &str, mut line: u32, after_token: bool) -> Vec<CommentBlock> {
+    let first_line = line;
+    let mut blocks: Vec<CommentBlock> = Vec::new();
+    let mut rem = gap;
+    while !rem.is_empty() {
+        if let Some(comment) = rem.strip_prefix("//") {
+            let text_len = comment.find('\n').unwrap_or(comment.len());
+            let text = &comment[..text_len];
+            match blocks.last_mut() {
+                Some(b)
+                    if b.line_comments
+                        && b.end_line + 1 == line
+                        && !(after_token && b.start_line == first_line) =>
+                {
+                    b.end_line = line;
+                    b.text.push_str(text);
+                    b.text.push('\n');
+                }
+                _ => blocks.push(CommentBlock {
+                    line_comments: true,
+                    start_line: line,
+                    end_line: line,
+                    text: format!("{}\n", text),
+                }),
+            }
+            rem = &comment[text_len..];
+        } else if let Some(comment) = rem.strip_prefix("/*") {
+            let text_len = comment.find("*/").unwrap_or(comment.len());
+            let text = &comment[..text_len];
+            let start_line = line;
+            line += text.matches('\n').count() as u32;
+            blocks.push(CommentBlock {
+                line_comments: false,
+                start_line,
+                end_line: line,
+                text: text.to_owned(),
+            });
+            rem = comment.get(text_len + 2..).unwrap_or("");
+        } else {
+            let c = rem.chars().next().unwrap();
+            if c == '\n' {
+                line += 1;
+            }
+            rem = &rem[c.len_utf8()..];
+        }
+    }
+    blocks
+}
+
+impl<'a> SourceComments<'a> {
+    /// Tokenize source, fails if the source cannot be tokenized
+    pub fn new(source: &'a str) -> anyhow::Result<SourceComments<'a>> {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+
+        let mut starts = Vec::new();
+        let mut lexer = Lexer::new(source, ParserLanguage::Proto);
+        while let Some(t) = lexer.next_token()? {
+            let line_start = line_starts[t.loc.line as usize - 1];
+            let start = line_start
+                + source[line_start..]
+                    .char_indices()
+                    .nth(t.loc.col as usize - 1)
+                    .map_or(source.len() - line_start, |(i, _)| i);
+            starts.push((t, start));
+        }
+
+        // After reading a token the lexer is at the start of the next token
+        let mut tokens = Vec::with_capacity(starts.len());
+        for (i, (t, start)) in starts.iter().enumerate() {
+            let next = starts.get(i + 1).map_or(source.len(), |(_, s)| *s);
+            let end = token_end(source, *start, next, &t.token);
+            let end_line = t.loc.line + source[*start..end].matches('\n').count() as u32;
+            tokens.push(TokenRange {
+                token: t.token.clone(),
+                loc: t.loc,
+                start: *start,
+                end,
+                end_line,
+            });
+        }
+
+        Ok(SourceComments { source, tokens })
+    }
+
+    fn gap_blocks(&self, after: Option<usize>) -> (Vec<CommentBlock>, Option<u32>) {
+        let (start, line) = match after {
+            Some(i) => (self.tokens[i].end, self.tokens[i].end_line),
+            None => (0, 1),
+        };
+        let next = after.map_or(0, |i| i + 1);
+        let (end, next_line) = match self.tokens.get(next) {
+            Some(t) => (t.start, Some(t.loc.line)),
+            None => (self.source.len(), None),
+        };
+        (
+            comment_blocks(&self.source[start..end], line, after.is_some()),
+            next_line,
+        )
+    }
+
+    /// If block after token `i` on the same or the next line is its trailing comment.
+    ///
+    /// Only comments after `;`, `{` or `}` are trailing. Comments after `}`
+    /// do not belong to any declaration.
+    fn is_trailing(&self, i: usize, blocks: &[CommentBlock], next_line: Option<u32>) -> bool {
+        let token = &self.tokens[i];
+        if !matches!(
+            token.token,
+            Token::Symbol(';') | Token::Symbol('{') | Token::Symbol('}')
+        ) {
+            return false;
+        }
+        let first = match blocks.first() {
+            Some(first) => first,
+            None => return false,
+        };
+        if first.start_line == token.end_line {
+            return true;
+        }
+        let followed_by_blank = match blocks.get(1) {
+            Some(b) => b.start_line > first.end_line + 1,
+            None => next_line.is_none_or(|l| l > first.end_line + 1),
+        };
+        first.start_line == token.end_line + 1 && followed_by_blank
+    }
+
+    /// Index of `;` ending the declaration starting with token `i`,
+    /// or of `{` opening its body
+    fn declaration_end(&self, i: usize) -> usize {
+        // Brackets and braces of values, e. g. `option x = { ... };`
+        let mut nesting = 0;
+        for j in i..self.tokens.len() {
+            match self.tokens[j].token {
+                Token::Symbol('(') | Token::Symbol('[') => nesting += 1,
+                Token::Symbol(')') | Token::Symbol(']') => nesting -= 1,
+                Token::Symbol('{') => {
+                    let value = nesting > 0
+                        || j > 0 && matches!(self.tokens[j - 1].token, Token::Symbol('=' | ':'));
+                    if !value {
+                        return j;
+                    }
+                    nesting += 1;
+                }
+                Token::Symbol('}') => nesting -= 1,
+                Token::Symbol(';') if nesting == 0 => return j,
+                _ => {}
+            }
+        }
+        self.tokens.len() - 1
+    }
+
+    /// Comments of the declaration starting at `loc`, e. g. `WithLoc::loc`.
+    ///
+    /// Returns no comments if no token starts at `loc`.
+    pub fn comments(&self, loc: Loc) -> Comments {
+        let i = match self.tokens.binary_search_by_key(&loc, |t| t.loc) {
+            Ok(i) => i,
+            Err(_) => return Comments::default(),
+        };
+
+        let mut r = Comments::default();
+
+        let prev = i.checked_sub(1);
+        let (mut blocks, _) = self.gap_blocks(prev);
+        if let Some(prev) = prev {
+            if self.is_trailing(prev, &blocks, Some(loc.line)) {
+                blocks.remove(0);
+            }
+        }
+        if let Some(last) = blocks.last() {
+            if last.end_line + 1 >= loc.line {
+                r.leading = blocks.pop().map(|b| b.text);
+            }
+        }
+        r.detached = blocks.into_iter().map(|b| b.text).collect();
+
+        let end = self.declaration_end(i);
+        let (blocks, next_line) = self.gap_blocks(Some(end));
+        if self.is_trailing(end, &blocks, next_line) {
+            r.trailing = blocks.into_iter().next().map(|b| b.text);
+        }
+
+        r
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn comments(source: &str, line: u32, col: u32) -> Comments {
+        SourceComments::new(source)
+            .unwrap()
+            .comments(Loc { line, col })
+    }
+
+    fn detached(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_leading_and_trailing() {
+        let source = r#"// leading
+message M {} // not trailing
+
+enum E { // trailing
+  Z = 0; // value
+}
+"#;
+        assert_eq!(
+            Comments {
+                leading: Some(" leading\n".to_owned()),
+                trailing: None,
+                detached: Vec::new(),
+            },
+            comments(source, 2, 1)
+        );
+        assert_eq!(
+            Some(" trailing\n".to_owned()),
+            comments(source, 4, 1).trailing
+        );
+        assert_eq!(Some(" value\n".to_owned()), comments(source, 5, 3).trailing);
+    }
+
+    #[test]
+    fn test_detached() {
+        let source = "// a\n// b\n\n/* c */\n\n// leading\nmessage M {}\n";
+        assert_eq!(
+            Comments {
+                leading: Some(" leading\n".to_owned()),
+                trailing: None,
+                detached: detached(&[" a\n b\n", " c "]),
+            },
+            comments(source, 7, 1)
+        );
+    }
+
+    #[test]
+    fn test_blank_line_before_declaration() {
+        let source = "// detached\n\nmessage M {}\n";
+        assert_eq!(
+            Comments {
+                leading: None,
+                trailing: None,
+                detached: detached(&[" detached\n"]),
+            },
+            comments(source, 3, 1)
+        );
+    }
+
+    #[test]
+    fn test_trailing_on_next_line() {
+        let source = r#"message M {
+  int32 a = 1;
+  // trailing of a
+
+  int32 b = 2;
+  // leading of c
+  int32 c = 3;
+}
+"#;
+        assert_eq!(
+            Some(" trailing of a\n".to_owned()),
+            comments(source, 2, 3).trailing
+        );
+        let b = comments(source, 5, 3);
+        assert_eq!((None, None), (b.leading, b.trailing));
+        assert!(b.detached.is_empty());
+        assert_eq!(
+            Some(" leading of c\n".to_owned()),
+            comments(source, 7, 3).leading
+        );
+    }
+
+    #[test]
+    fn test_no_declaration_at_loc() {
+        assert_eq!(Comments::default(), comments("// c\nmessage M {}\n", 2, 2));
+    }
+
+    #[test]
+    fn test_oneof_comments() {
+        let source = r#"message M {
+  // leading of o
+  oneof o { // trailing of o
+    // leading of a
+    int32 a = 1;
+  }
+  oneof p {
+    // leading of b
+    int32 b = 2;
+  }
+}
+"#;
+        assert_eq!(
+            Comments {
+                leading: Some(" leading of o\n".to_owned()),
+                trailing: Some(" trailing of o\n".to_owned()),
+                detached: Vec::new(),
+            },
+            comments(source, 3, 3)
+        );
+        assert_eq!(
+            Some(" leading of a\n".to_owned()),
+            comments(source, 5, 5).leading
+        );
+        // Comment on the line after `{` is not trailing without a blank line
+        assert_eq!(None, comments(source, 7, 3).trailing);
+        assert_eq!(
+            Some(" leading of b\n".to_owned()),
+            comments(source, 9, 5).leading
+        );
+    }
+
+    #[test]
+    fn test_trailing_after_line_comments_on_brace_line() {
+        // Line comment after `{` does not merge with the comment below
+        let source = "message M { // trailing\n  // leading\n  int32 a = 1;\n}\n";
+        assert_eq!(
+            Some(" trailing\n".to_owned()),
+            comments(source, 1, 1).trailing
+        );
+        assert_eq!(
+            Some(" leading\n".to_owned()),
+            comments(source, 3, 3).leading
+        );
+    }
+
+    #[test]
+    fn test_trailing_after_aggregate_option() {
+        let source = "message M {\n  option (x) = { a: { b: 1 } }; // trailing\n}\n";
+        assert_eq!(
+            Some(" trailing\n".to_owned()),
+            comments(source, 2, 3).trailing
+        );
+    }
+}
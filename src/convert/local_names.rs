@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use crate::convert::type_refs::for_each_type_ref_mut;
+use crate::convert::TypeResolver;
+use crate::model;
+use crate::model::Loc;
+use crate::protobuf_abs_path::{ProtobufAbsPath, ProtobufAbsPathRef};
+use crate::protobuf_ident::ProtobufIdentRef;
+use crate::protobuf_path::ProtobufPath;
+use crate::{ParserError, ParserErrorAt};
+
+/// Rewrites relative type references to absolute paths
+/// when they point to types declared in the same file.
+struct LocalNameResolver<'a> {
+    type_resolver: TypeResolver<'a>,
+}
+
+impl<'a> LocalNameResolver<'a> {
+    /// If name starts with a type declared in this file visible from scope.
+    fn is_local(&self, scope: &ProtobufAbsPathRef, first: &ProtobufIdentRef) -> bool {
+        scope.self_and_parents().into_iter().any(|p| {
+            let mut path = p.to_owned();
+            path.push_simple(first);
+            self.type_resolver
+                .find_message_or_enum_by_abs_name(&path)
+                .is_ok()
+        })
+    }
+
+    fn path(
+        &self,
+        scope: &ProtobufAbsPathRef,
+        loc: Loc,
+        path: &mut ProtobufPath,
+    ) -> anyhow::Result<()> {
+        let rel = match path {
+            ProtobufPath::Abs(..) => return Ok(()),
+            ProtobufPath::Rel(rel) => rel.clone(),
+        };
+        match self
+            .type_resolver
+            .resolve_message_or_enum(scope, &ProtobufPath::Rel(rel.clone()))
+        {
+            Ok(resolved) => {
+                *path = ProtobufPath::Abs(resolved.full_name);
+                Ok(())
+            }
+            Err(_) => match rel.split_first_rem() {
+                // Name refers to a local type, but the rest cannot be resolved
+                Some((first, _)) if self.is_local(scope, first) => {
+                    let error = ParserError::UnresolvedLocalName(rel.clone(), scope.to_owned());
+                    Err(ParserErrorAt { error, loc }.into())
+                }
+                // Must be declared in another file
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// Resolve relative type references to types declared in the same file.
+///
+/// References which cannot be resolved within the file are left relative,
+/// unless their first component names a type declared in this file.
+pub(crate) fn resolve_local_names(file: &mut model::FileDescriptor) -> anyhow::Result<()> {
+    let snapshot = file.clone();
+    let resolver = LocalNameResolver {
+        type_resolver: TypeResolver {
+            current_file: &snapshot,
+            deps: &[],
+        },
+    };
+
+    for_each_type_ref_mut(file, |scope, loc, path| resolver.path(scope, loc, path))
+}
+
+/// Resolve type references to types declared in the file or in `known_types`,
+/// searching the scope and its parents like protoc.
+///
+/// Fails on references to types which are neither.
+pub(crate) fn resolve_known_types(
+    file: &mut model::FileDescriptor,
+    known_types: &HashSet<ProtobufAbsPath>,
+) -> anyhow::Result<()> {
+    let snapshot = file.clone();
+    let type_resolver = TypeResolver {
+        current_file: &snapshot,
+        deps: &[],
+    };
+
+    for_each_type_ref_mut(file, |scope, loc, path| {
+        let candidates = match &*path {
+            ProtobufPath::Abs(abs) => vec![abs.clone()],
+            ProtobufPath::Rel(rel) => scope
+                .self_and_parents()
+                .into_iter()
+                .map(|p| ProtobufAbsPath::concat(p, rel))
+                .collect(),
+        };
+        for candidate in candidates {
+            if known_types.contains(&candidate)
+                || type_resolver
+                    .find_message_or_enum_by_abs_name(&candidate)
+                    .is_ok()
+            {
+                *path = ProtobufPath::Abs(candidate);
+                return Ok(());
+            }
+        }
+        let error = ParserError::UnknownType(path.clone(), scope.to_owned());
+        Err(ParserErrorAt { error, loc }.into())
+    })
+}
@@ -14,13 +14,23 @@ use crate::{
     model,
     protobuf_abs_path::{ProtobufAbsPath, ProtobufAbsPathRef},
     protobuf_ident::{ProtobufIdent, ProtobufIdentRef},
+    protobuf_path::ProtobufPath,
+    str_lit::StrLitEx,
+    ParserError, ParserErrorAt, MAX_FIELD_NUMBER,
 };
 
+pub(crate) use self::local_names::{resolve_known_types, resolve_local_names};
 use self::option_resolver::{OptionResolver, ProtobufOptions};
+pub use self::prost::{to_prost_meta, ProstField, ProstMessage, ProstMeta, ProstType};
+pub(crate) use self::rename::rename_type;
 use self::type_resolver::MessageOrEnum;
 pub use self::type_resolver::TypeResolver;
 
+mod local_names;
 mod option_resolver;
+mod prost;
+mod rename;
+mod type_refs;
 mod type_resolver;
 
 #[derive(Debug, thiserror::Error)]
@@ -192,8 +202,16 @@ impl<'a> Resolver<'a> {
 
         output.set_name(name.to_owned());
 
+        // Group is a message type, so its fields are resolved within it
+        let mut group_scope = scope.to_owned();
+        group_scope.push_simple(ProtobufIdentRef::new(name));
+
         for f in fields {
-            output.field.push(self.field(scope, f, None)?);
+            output.field.push(self.field(&group_scope, f, None)?);
+            if let model::FieldType::Group(group) = &f.t.typ {
+                let nested = self.group_message(&group_scope, &group.name, &group.fields)?;
+                output.nested_type.push(nested);
+            }
         }
 
         Ok(output)
@@ -259,16 +277,19 @@ impl<'a> Resolver<'a> {
 
         {
             let mut fields = Vec::new();
+            // Synthetic oneofs of proto3 optional fields must follow all real oneofs
+            let mut synthetic_oneofs = Vec::new();
+            let real_oneof_count = input.oneofs().len();
 
             for fo in &input.fields {
                 match &fo.t {
                     model::FieldOrOneOf::Field(f) => {
                         let oneof_index = if self.is_proto3_optional(f) {
-                            let oneof_index = output.oneof_decl.len() as i32;
+                            let oneof_index = real_oneof_count + synthetic_oneofs.len();
                             let mut oneof = OneofDescriptorProto::new();
                             oneof.set_name(format!("_{}", f.name));
-                            output.oneof_decl.push(oneof);
-                            Some(oneof_index)
+                            synthetic_oneofs.push(oneof);
+                            Some(oneof_index as i32)
                         } else {
                             None
                         };
@@ -285,19 +306,22 @@ impl<'a> Resolver<'a> {
             }
 
             output.field = fields;
+            output.oneof_decl.extend(synthetic_oneofs);
         }
 
         for ext in &input.extension_ranges {
             let mut extension_range = protobuf::descriptor::descriptor_proto::ExtensionRange::new();
             extension_range.set_start(*ext.start());
+            // End is exclusive
             extension_range.set_end(*ext.end() + 1);
             output.extension_range.push(extension_range);
         }
+        // Extensions declared in a message are resolved within it
         for ext in &input.extensions {
-            let mut extension = self.field(scope, &ext.t.field, None)?;
+            let mut extension = self.field(&nested_scope, &ext.t.field, None)?;
             extension.set_extendee(
                 self.type_resolver
-                    .resolve_message_or_enum(scope, &ext.t.extendee)?
+                    .resolve_message_or_enum(&nested_scope, &ext.t.extendee)?
                     .full_name
                     .path,
             );
@@ -315,25 +339,25 @@ impl<'a> Resolver<'a> {
         Ok(output)
     }
 
+    fn service_method_type(&self, name: &ProtobufPath) -> anyhow::Result<String> {
+        let scope = &self.current_file.package;
+        let resolved = self.type_resolver.resolve_message_or_enum(scope, name)?;
+        match resolved.t {
+            MessageOrEnum::Message(..) => Ok(resolved.full_name.to_string()),
+            MessageOrEnum::Enum(..) => {
+                Err(ParserError::RpcTypeMustBeMessage(name.to_string()).into())
+            }
+        }
+    }
+
     fn service_method(
         &self,
         input: &model::Method,
     ) -> anyhow::Result<protobuf::descriptor::MethodDescriptorProto> {
-        let scope = &self.current_file.package;
         let mut output = protobuf::descriptor::MethodDescriptorProto::new();
         output.set_name(input.name.clone());
-        output.set_input_type(
-            self.type_resolver
-                .resolve_message_or_enum(scope, &input.input_type)?
-                .full_name
-                .to_string(),
-        );
-        output.set_output_type(
-            self.type_resolver
-                .resolve_message_or_enum(scope, &input.output_type)?
-                .full_name
-                .to_string(),
-        );
+        output.set_input_type(self.service_method_type(&input.input_type)?);
+        output.set_output_type(self.service_method_type(&input.output_type)?);
 
         if input.client_streaming {
             output.set_client_streaming(input.client_streaming);
@@ -397,7 +421,7 @@ impl<'a> Resolver<'a> {
             let default = match output.type_() {
                 protobuf::descriptor::field_descriptor_proto::Type::TYPE_STRING => {
                     if let model::ProtobufConstant::String(ref s) = default {
-                        s.decode_utf8()?
+                        s.decode_proto_utf8()?
                     } else {
                         return Err(ConvertError::DefaultValueIsNotStringLiteral.into());
                     }
@@ -405,7 +429,7 @@ impl<'a> Resolver<'a> {
                 protobuf::descriptor::field_descriptor_proto::Type::TYPE_BYTES => {
                     if let model::ProtobufConstant::String(ref s) = default {
                         let mut buf = String::new();
-                        escape_bytes_to(&s.decode_bytes()?, &mut buf);
+                        escape_bytes_to(&s.decode_lenient()?, &mut buf);
                         buf
                     } else {
                         return Err(ConvertError::DefaultValueIsNotStringLiteral.into());
@@ -598,6 +622,88 @@ fn label(input: Option<model::Rule>) -> protobuf::descriptor::field_descriptor_p
     }
 }
 
+/// Check that method input and output types are not enums.
+///
+/// Types which cannot be resolved within the file are not checked.
+pub(crate) fn check_method_types(file: &model::FileDescriptor) -> anyhow::Result<()> {
+    let type_resolver = TypeResolver {
+        current_file: file,
+        deps: &[],
+    };
+    for service in &file.services {
+        for method in &service.t.methods {
+            for (name, loc) in [
+                (&method.input_type, method.input_type_loc),
+                (&method.output_type, method.output_type_loc),
+            ] {
+                if let Ok(WithFullName {
+                    t: MessageOrEnum::Enum(..),
+                    ..
+                }) = type_resolver.resolve_message_or_enum(&file.package, name)
+                {
+                    let error = ParserError::RpcTypeMustBeMessage(name.to_string());
+                    return Err(ParserErrorAt { error, loc }.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that extension numbers are not greater than `MAX_FIELD_NUMBER`,
+/// unless the extendee is a MessageSet.
+///
+/// Extensions of types which cannot be resolved within the file are not checked.
+pub(crate) fn check_extension_numbers(file: &model::FileDescriptor) -> anyhow::Result<()> {
+    let type_resolver = TypeResolver {
+        current_file: file,
+        deps: &[],
+    };
+    let mut scoped_extensions: Vec<(ProtobufAbsPath, &[model::WithLoc<model::Extension>])> =
+        vec![(file.package.clone(), &file.extensions)];
+    let mut messages: Vec<(ProtobufAbsPath, &model::Message)> = file
+        .messages
+        .iter()
+        .map(|m| (file.package.clone(), &m.t))
+        .collect();
+    while let Some((scope, message)) = messages.pop() {
+        let mut nested_scope = scope;
+        nested_scope.push_simple(ProtobufIdentRef::new(&message.name));
+        for m in &message.messages {
+            messages.push((nested_scope.clone(), &m.t));
+        }
+        scoped_extensions.push((nested_scope, &message.extensions));
+    }
+
+    for (scope, extensions) in scoped_extensions {
+        for extension in extensions {
+            let number = extension.t.field.t.number;
+            if number <= MAX_FIELD_NUMBER {
+                continue;
+            }
+            let message_set =
+                match type_resolver.resolve_message_or_enum(&scope, &extension.t.extendee) {
+                    Ok(WithFullName {
+                        t: MessageOrEnum::Message(m),
+                        ..
+                    }) => m.is_message_set(),
+                    _ => continue,
+                };
+            if !message_set {
+                let error = ParserError::FieldNumberOutOfRange(number, MAX_FIELD_NUMBER);
+                return Err(ParserErrorAt {
+                    error,
+                    loc: extension.loc,
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill `dependency` in import order. `public_dependency` and `weak_dependency`
+/// hold indices into `dependency` of `import public` and `import weak`.
 pub(crate) fn populate_dependencies(
     input: &model::FileDescriptor,
     output: &mut protobuf::descriptor::FileDescriptorProto,
@@ -691,3 +797,152 @@ pub fn file_descriptor(
 
     Ok(output)
 }
+
+#[cfg(test)]
+mod test {
+    use protobuf::descriptor::file_options::OptimizeMode;
+    use protobuf::descriptor::FileDescriptorProto;
+
+    use super::*;
+
+    fn parse(input: &str) -> model::FileDescriptor {
+        model::FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn descriptor(input: &str) -> FileDescriptorProto {
+        file_descriptor(&parse(input)).unwrap()
+    }
+
+    #[test]
+    fn test_builtin_enum_option() {
+        let d = descriptor("option optimize_for = CODE_SIZE;");
+        assert_eq!(OptimizeMode::CODE_SIZE, d.options.optimize_for());
+    }
+
+    #[test]
+    fn test_builtin_enum_option_unknown_value() {
+        let e = file_descriptor(&parse("option optimize_for = FAST;")).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("has no value named `FAST`"),
+            "{:#}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_dependency_indices() {
+        let file = parse(
+            r#"
+            import "a.proto";
+            import weak "b.proto";
+            import public "c.proto";
+            import "d.proto";
+            import public "e.proto";
+            "#,
+        );
+        let mut d = FileDescriptorProto::new();
+        populate_dependencies(&file, &mut d);
+        assert_eq!(
+            vec!["a.proto", "b.proto", "c.proto", "d.proto", "e.proto"],
+            d.dependency
+        );
+        assert_eq!(vec![2, 4], d.public_dependency);
+        assert_eq!(vec![1], d.weak_dependency);
+    }
+
+    #[test]
+    fn test_oneof_index() {
+        let file = descriptor(
+            r#"
+            syntax = "proto3";
+            message M {
+                int32 a = 1;
+                optional int32 b = 2;
+                oneof x {
+                    int32 c = 3;
+                    int32 d = 4;
+                }
+                int32 e = 5;
+                oneof y { int32 f = 6; }
+                optional int32 g = 7;
+            }
+            "#,
+        );
+        let m = &file.message_type[0];
+        let oneofs: Vec<&str> = m.oneof_decl.iter().map(|o| o.name()).collect();
+        assert_eq!(vec!["x", "y", "_b", "_g"], oneofs);
+        let fields: Vec<(&str, Option<i32>)> =
+            m.field.iter().map(|f| (f.name(), f.oneof_index)).collect();
+        assert_eq!(
+            vec![
+                ("a", None),
+                ("b", Some(2)),
+                ("c", Some(0)),
+                ("d", Some(0)),
+                ("e", None),
+                ("f", Some(1)),
+                ("g", Some(3)),
+            ],
+            fields
+        );
+    }
+
+    #[test]
+    fn test_message_set_extension_range_end() {
+        let d = descriptor(
+            r#"
+            message S {
+                option message_set_wire_format = true;
+                extensions 4 to max, 10 to 536870911;
+            }
+            message M {
+                extensions 4 to max;
+            }
+            "#,
+        );
+        let ends: Vec<Vec<(i32, i32)>> = d
+            .message_type
+            .iter()
+            .map(|m| {
+                m.extension_range
+                    .iter()
+                    .map(|r| (r.start(), r.end()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                vec![(4, i32::MAX), (10, MAX_FIELD_NUMBER + 1)],
+                vec![(4, MAX_FIELD_NUMBER + 1)]
+            ],
+            ends
+        );
+    }
+
+    #[test]
+    fn test_nested_extension_and_group_scope() {
+        let file = descriptor(
+            r#"
+            package p;
+            message A { extensions 100 to 200; }
+            message M {
+                message N {}
+                extend A { optional N n = 100; }
+                optional group G = 1 {
+                    optional group H = 2 {
+                        optional N x = 3;
+                    }
+                }
+            }
+            "#,
+        );
+        let m = &file.message_type[1];
+        assert_eq!(".p.A", m.extension[0].extendee());
+        assert_eq!(".p.M.N", m.extension[0].type_name());
+
+        let g = m.nested_type.iter().find(|t| t.name() == "G").unwrap();
+        let h = &g.nested_type[0];
+        assert_eq!("H", h.name());
+        assert_eq!(".p.M.N", h.field[0].type_name());
+    }
+}
@@ -28,6 +28,7 @@ use crate::protobuf_abs_path::ProtobufAbsPathRef;
 use crate::protobuf_ident::ProtobufIdentRef;
 use crate::protobuf_path::ProtobufPath;
 use crate::protobuf_rel_path::ProtobufRelPathRef;
+use crate::str_lit::StrLitEx;
 use crate::ProtobufAbsPath;
 use crate::ProtobufIdent;
 use crate::ProtobufRelPath;
@@ -177,7 +178,7 @@ pub trait ProtobufOptions {
 
     fn by_name_string(&self, name: &str) -> anyhow::Result<Option<String>> {
         match self.by_name(name) {
-            Some(ProtobufConstant::String(s)) => s.decode_utf8().map(Some).map_err(|err| anyhow!(err)),
+            Some(ProtobufConstant::String(s)) => s.decode_proto_utf8().map(Some).map_err(|err| anyhow!(err)),
             Some(c) => Err(OptionResolverError::WrongOptionType("string", c.to_string()).into()),
             None => Ok(None),
         }
@@ -187,7 +188,7 @@ pub trait ProtobufOptions {
 impl<'a> ProtobufOptions for &'a [model::ProtobufOption] {
     fn by_name(&self, name: &str) -> Option<&ProtobufConstant> {
         let option_name = ProtobufOptionName::simple(name);
-        for model::ProtobufOption { name, value } in *self {
+        for model::ProtobufOption { name, value, .. } in *self {
             if name == &option_name {
                 return Some(value);
             }
@@ -504,16 +505,22 @@ impl<'a> OptionResolver<'a> {
                                     )
                                 }
                             };
-                            let u = self
-                                .option_value_field_to_unknown_value(
-                                    ma,
-                                    v,
-                                    n,
-                                    &f.typ,
-                                    option_name_for_diag,
-                                )
-                                .map_err(OptionResolverError::OtherError)?;
-                            unknown_fields.add_value(f.number as u32, u);
+                            let values = match v {
+                                ProtobufConstant::Repeated(values) => values.iter().collect(),
+                                v => vec![v],
+                            };
+                            for v in values {
+                                let u = self
+                                    .option_value_field_to_unknown_value(
+                                        ma,
+                                        v,
+                                        n,
+                                        &f.typ,
+                                        option_name_for_diag,
+                                    )
+                                    .map_err(OptionResolverError::OtherError)?;
+                                unknown_fields.add_value(f.number as u32, u);
+                            }
                         }
                         ProtobufConstantMessageFieldName::Extension(..) => {
                             // TODO: implement extension fields in constants
@@ -594,9 +601,9 @@ impl<'a> OptionResolver<'a> {
             },
             ProtobufConstant::String(ref s) => match field_type {
                 TypeResolved::String => {
-                    return Ok(UnknownValue::LengthDelimited(s.decode_utf8()?.into_bytes()))
+                    return Ok(UnknownValue::LengthDelimited(s.decode_proto_utf8()?.into_bytes()))
                 }
-                TypeResolved::Bytes => return Ok(UnknownValue::LengthDelimited(s.decode_bytes()?)),
+                TypeResolved::Bytes => return Ok(UnknownValue::LengthDelimited(s.decode_lenient()?)),
                 _ => {}
             },
             ProtobufConstant::Ident(ident) => if let TypeResolved::Enum(e) = &field_type {
@@ -626,6 +633,7 @@ impl<'a> OptionResolver<'a> {
                     option_name_for_diag,
                 );
             }
+            ProtobufConstant::Repeated(..) => {}
         };
 
         Err(OptionResolverError::UnsupportedExtensionType(
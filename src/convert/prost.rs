@@ -0,0 +1,326 @@
+use crate::case_convert::snake_case;
+use crate::convert::type_resolver::MessageOrEnum;
+use crate::convert::TypeResolver;
+use crate::model;
+use crate::protobuf_abs_path::{ProtobufAbsPath, ProtobufAbsPathRef};
+use crate::protobuf_ident::ProtobufIdentRef;
+
+/// Field type as represented by prost
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProstType {
+    /// Scalar with its name in `#[prost(...)]` attribute and Rust type,
+    /// e. g. `sint32` and `i32`
+    Scalar {
+        proto: &'static str,
+        rust: &'static str,
+    },
+    /// Enum, stored as `i32`
+    Enum(ProtobufAbsPath),
+    /// Message or group
+    Message(ProtobufAbsPath),
+    /// Map, a `HashMap` of key and value types
+    Map(Box<ProstType>, Box<ProstType>),
+}
+
+/// Message field as represented by prost
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProstField {
+    /// Field name in `.proto`
+    pub name: String,
+    /// Rust field name
+    pub rust_name: String,
+    pub tag: i32,
+    pub typ: ProstType,
+    /// `Vec<T>`
+    pub repeated: bool,
+    /// `Option<T>`; fields of a oneof are variants of an enum
+    /// stored in an `Option` instead
+    pub optional: bool,
+    /// Name of the oneof the field belongs to
+    pub oneof: Option<String>,
+}
+
+/// Message as represented by prost
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProstMessage {
+    pub full_name: ProtobufAbsPath,
+    pub fields: Vec<ProstField>,
+}
+
+/// Messages of a file with fields described the way prost generates them.
+///
+/// Nested messages and groups are listed after their parent.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProstMeta {
+    pub messages: Vec<ProstMessage>,
+}
+
+struct ProstMetaBuilder<'a> {
+    file: &'a model::FileDescriptor,
+    type_resolver: TypeResolver<'a>,
+    messages: Vec<ProstMessage>,
+}
+
+fn scalar(proto: &'static str, rust: &'static str) -> ProstType {
+    ProstType::Scalar { proto, rust }
+}
+
+impl<'a> ProstMetaBuilder<'a> {
+    fn field_type(
+        &self,
+        scope: &ProtobufAbsPathRef,
+        field_type: &model::FieldType,
+    ) -> anyhow::Result<ProstType> {
+        Ok(match field_type {
+            model::FieldType::Int32 => scalar("int32", "i32"),
+            model::FieldType::Int64 => scalar("int64", "i64"),
+            model::FieldType::Uint32 => scalar("uint32", "u32"),
+            model::FieldType::Uint64 => scalar("uint64", "u64"),
+            model::FieldType::Sint32 => scalar("sint32", "i32"),
+            model::FieldType::Sint64 => scalar("sint64", "i64"),
+            model::FieldType::Bool => scalar("bool", "bool"),
+            model::FieldType::Fixed64 => scalar("fixed64", "u64"),
+            model::FieldType::Sfixed64 => scalar("sfixed64", "i64"),
+            model::FieldType::Double => scalar("double", "f64"),
+            model::FieldType::String => scalar("string", "String"),
+            model::FieldType::Bytes => scalar("bytes", "Vec<u8>"),
+            model::FieldType::Fixed32 => scalar("fixed32", "u32"),
+            model::FieldType::Sfixed32 => scalar("sfixed32", "i32"),
+            model::FieldType::Float => scalar("float", "f32"),
+            model::FieldType::MessageOrEnum(path) => {
+                let resolved = self.type_resolver.resolve_message_or_enum(scope, path)?;
+                match resolved.t {
+                    MessageOrEnum::Message(..) => ProstType::Message(resolved.full_name),
+                    MessageOrEnum::Enum(..) => ProstType::Enum(resolved.full_name),
+                }
+            }
+            model::FieldType::Map(key_value) => ProstType::Map(
+                Box::new(self.field_type(scope, &key_value.0)?),
+                Box::new(self.field_type(scope, &key_value.1)?),
+            ),
+            model::FieldType::Group(group) => {
+                let mut full_name = scope.to_owned();
+                full_name.push_simple(ProtobufIdentRef::new(&group.name));
+                ProstType::Message(full_name)
+            }
+        })
+    }
+
+    fn field(
+        &self,
+        scope: &ProtobufAbsPathRef,
+        field: &model::Field,
+        oneof: Option<&str>,
+    ) -> anyhow::Result<ProstField> {
+        let typ = self.field_type(scope, &field.typ)?;
+        let repeated = field.rule == Some(model::Rule::Repeated);
+        let optional = oneof.is_none()
+            && field.has_presence(self.file.syntax, false, None, |_| {
+                matches!(typ, ProstType::Message(..))
+            });
+        Ok(ProstField {
+            name: field.name.clone(),
+            rust_name: snake_case(&field.name),
+            tag: field.number,
+            typ,
+            repeated,
+            optional,
+            oneof: oneof.map(|o| o.to_owned()),
+        })
+    }
+
+    fn group(&mut self, scope: &ProtobufAbsPathRef, field: &model::Field) -> anyhow::Result<()> {
+        if let model::FieldType::Group(group) = &field.typ {
+            let mut full_name = scope.to_owned();
+            full_name.push_simple(ProtobufIdentRef::new(&group.name));
+            let fields = group
+                .fields
+                .iter()
+                .map(|f| self.field(&full_name, &f.t, None))
+                .collect::<anyhow::Result<_>>()?;
+            self.messages.push(ProstMessage {
+                full_name: full_name.clone(),
+                fields,
+            });
+            for f in &group.fields {
+                self.group(&full_name, &f.t)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn message(
+        &mut self,
+        scope: &ProtobufAbsPathRef,
+        message: &model::Message,
+    ) -> anyhow::Result<()> {
+        let mut full_name = scope.to_owned();
+        full_name.push_simple(ProtobufIdentRef::new(&message.name));
+
+        let mut fields = Vec::new();
+        for fo in &message.fields {
+            match &fo.t {
+                model::FieldOrOneOf::Field(f) => fields.push(self.field(&full_name, &f.t, None)?),
+                model::FieldOrOneOf::OneOf(o) => {
+                    for f in &o.fields {
+                        fields.push(self.field(&full_name, &f.t, Some(&o.name))?);
+                    }
+                }
+            }
+        }
+        self.messages.push(ProstMessage {
+            full_name: full_name.clone(),
+            fields,
+        });
+
+        for f in message.regular_fields_including_in_oneofs() {
+            self.group(&full_name, &f.t)?;
+        }
+        for m in &message.messages {
+            self.message(&full_name, &m.t)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe messages of a file the way prost generates Rust structs for them.
+///
+/// Referenced types must be declared in the file.
+pub fn to_prost_meta(file: &model::FileDescriptor) -> anyhow::Result<ProstMeta> {
+    let mut builder = ProstMetaBuilder {
+        file,
+        type_resolver: TypeResolver {
+            current_file: file,
+            deps: &[],
+        },
+        messages: Vec::new(),
+    };
+    for m in &file.messages {
+        builder.message(&file.package, &m.t)?;
+    }
+    Ok(ProstMeta {
+        messages: builder.messages,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prost_meta(input: &str) -> ProstMeta {
+        let file = model::FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e));
+        to_prost_meta(&file).unwrap()
+    }
+
+    fn message(name: &str) -> ProstType {
+        ProstType::Message(ProtobufAbsPath::new(name))
+    }
+
+    fn field(
+        name: &str,
+        tag: i32,
+        typ: ProstType,
+        repeated: bool,
+        optional: bool,
+        oneof: Option<&str>,
+    ) -> ProstField {
+        ProstField {
+            name: name.to_owned(),
+            rust_name: name.to_owned(),
+            tag,
+            typ,
+            repeated,
+            optional,
+            oneof: oneof.map(|o| o.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_proto3_message() {
+        let meta = prost_meta(
+            r#"
+            syntax = "proto3";
+            package p;
+            message M {
+                sint32 n = 1;
+                optional string s = 2;
+                M m = 3;
+                E e = 4;
+                repeated bytes b = 5;
+                map<string, M> map = 6;
+                oneof o {
+                    int64 a = 7;
+                    Nested nested = 8;
+                }
+                message Nested {}
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let full_names: Vec<String> = meta
+            .messages
+            .iter()
+            .map(|m| m.full_name.to_string())
+            .collect();
+        assert_eq!(vec![".p.M", ".p.M.Nested"], full_names);
+
+        let map = ProstType::Map(
+            Box::new(scalar("string", "String")),
+            Box::new(message(".p.M")),
+        );
+        let e = ProstType::Enum(ProtobufAbsPath::new(".p.E"));
+        assert_eq!(
+            vec![
+                field("n", 1, scalar("sint32", "i32"), false, false, None),
+                field("s", 2, scalar("string", "String"), false, true, None),
+                field("m", 3, message(".p.M"), false, true, None),
+                field("e", 4, e, false, false, None),
+                field("b", 5, scalar("bytes", "Vec<u8>"), true, false, None),
+                field("map", 6, map, false, false, None),
+                field("a", 7, scalar("int64", "i64"), false, false, Some("o")),
+                field("nested", 8, message(".p.M.Nested"), false, false, Some("o")),
+            ],
+            meta.messages[0].fields
+        );
+    }
+
+    #[test]
+    fn test_proto2_group() {
+        let meta = prost_meta(
+            r#"
+            message M {
+                required int32 r = 1;
+                optional group G = 2 {
+                    optional fixed64 f = 3;
+                    repeated M m = 4;
+                }
+            }
+            "#,
+        );
+        assert_eq!(2, meta.messages.len());
+        let m = &meta.messages[0];
+        assert_eq!(".M", m.full_name.to_string());
+        assert!(m.fields[0].optional);
+        assert_eq!(message(".M.G"), m.fields[1].typ);
+        assert!(m.fields[1].optional);
+
+        let g = &meta.messages[1];
+        assert_eq!(".M.G", g.full_name.to_string());
+        assert_eq!(scalar("fixed64", "u64"), g.fields[0].typ);
+        assert!(g.fields[0].optional);
+        assert_eq!(message(".M"), g.fields[1].typ);
+        assert!(g.fields[1].repeated && !g.fields[1].optional);
+    }
+
+    #[test]
+    fn test_rust_name() {
+        let meta = prost_meta("syntax = \"proto3\"; message M { int32 fooBar = 1; }");
+        assert_eq!("foo_bar", meta.messages[0].fields[0].rust_name);
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        let file = model::FileDescriptor::parse("message M { optional X x = 1; }").unwrap();
+        assert!(to_prost_meta(&file).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use crate::convert::type_refs::for_each_type_ref_mut;
+use crate::convert::TypeResolver;
+use crate::model;
+use crate::model::WithLoc;
+use crate::protobuf_abs_path::ProtobufAbsPath;
+use crate::protobuf_abs_path::ProtobufAbsPathRef;
+use crate::protobuf_ident::ProtobufIdent;
+use crate::protobuf_ident::ProtobufIdentRef;
+use crate::protobuf_path::ProtobufPath;
+use crate::protobuf_rel_path::ProtobufRelPath;
+use crate::protobuf_rel_path::ProtobufRelPathRef;
+use crate::ParserError;
+
+/// Rename declaration of a message or enum with given full name.
+fn rename_declaration(
+    scope: &ProtobufAbsPathRef,
+    messages: &mut [WithLoc<model::Message>],
+    enums: &mut [WithLoc<model::Enumeration>],
+    from: &ProtobufAbsPathRef,
+    to: &ProtobufIdentRef,
+) -> bool {
+    let full_name = |name: &str| {
+        let mut path = scope.to_owned();
+        path.push_simple(ProtobufIdentRef::new(name));
+        path
+    };
+
+    for e in enums {
+        if full_name(&e.t.name).as_ref() == from {
+            e.t.name = to.as_str().to_owned();
+            return true;
+        }
+    }
+
+    for m in messages {
+        let path = full_name(&m.t.name);
+        if path.as_ref() == from {
+            m.t.name = to.as_str().to_owned();
+            return true;
+        }
+        if from.starts_with(&path) {
+            let m = &mut m.t;
+            return rename_declaration(&path, &mut m.messages, &mut m.enums, from, to);
+        }
+    }
+
+    false
+}
+
+/// Full name of the renamed type or the type nested in it at `rem`
+fn renamed_full_name(
+    from: &ProtobufAbsPathRef,
+    to: &ProtobufIdentRef,
+    rem: &ProtobufRelPathRef,
+) -> ProtobufAbsPath {
+    let mut renamed = from
+        .parent()
+        .unwrap_or(ProtobufAbsPathRef::root())
+        .to_owned();
+    renamed.push_simple(to);
+    renamed.push_relative(rem);
+    renamed
+}
+
+/// Rewrite reference resolved to `resolved` if it refers to renamed type
+/// or a type nested in it. Returns `true` if reference was changed.
+fn rename_reference(
+    path: &mut ProtobufPath,
+    resolved: &ProtobufAbsPathRef,
+    from: &ProtobufAbsPathRef,
+    to: &ProtobufIdentRef,
+) -> bool {
+    let rem = match resolved.remove_prefix(from) {
+        Some(rem) => rem,
+        None => return false,
+    };
+
+    match path {
+        ProtobufPath::Abs(abs) => {
+            *abs = renamed_full_name(from, to, rem);
+            true
+        }
+        ProtobufPath::Rel(rel) => {
+            // Relative name is a suffix of the resolved name,
+            // find the component naming the renamed type, if any.
+            let components: Vec<&ProtobufIdentRef> = rel.components().collect();
+            let rem_len = rem.components().count();
+            if components.len() <= rem_len {
+                return false;
+            }
+            let index = components.len() - rem_len - 1;
+            let renamed =
+                ProtobufRelPath::from_components(components.iter().enumerate().map(|(i, c)| {
+                    if i == index {
+                        to
+                    } else {
+                        *c
+                    }
+                }));
+            *rel = renamed;
+            true
+        }
+    }
+}
+
+/// Rename message or enum declared in the file, and update references to it
+/// and to types nested in it.
+///
+/// References which cannot be resolved within the file are not changed.
+/// A relative reference which would resolve to another type after renaming,
+/// e. g. because the new name shadows its target, is made absolute.
+/// Returns the number of changed references.
+pub(crate) fn rename_type(
+    file: &mut model::FileDescriptor,
+    from: &ProtobufAbsPath,
+    to: &ProtobufIdent,
+) -> anyhow::Result<usize> {
+    let snapshot = file.clone();
+    let type_resolver = TypeResolver {
+        current_file: &snapshot,
+        deps: &[],
+    };
+
+    type_resolver.find_message_or_enum_by_abs_name(from)?;
+
+    let mut renamed = from
+        .parent()
+        .unwrap_or(ProtobufAbsPathRef::root())
+        .to_owned();
+    renamed.push_simple(to);
+    if type_resolver
+        .find_message_or_enum_by_abs_name(&renamed)
+        .is_ok()
+    {
+        return Err(ParserError::TypeNameConflict(renamed).into());
+    }
+
+    let package = file.package.clone();
+    let mut renamed_file = snapshot.clone();
+    let renamed = rename_declaration(
+        &package,
+        &mut renamed_file.messages,
+        &mut renamed_file.enums,
+        from,
+        to,
+    );
+    assert!(renamed, "declaration of {} must exist", from);
+    let renamed_type_resolver = TypeResolver {
+        current_file: &renamed_file,
+        deps: &[],
+    };
+
+    let mut count = 0;
+    for_each_type_ref_mut(file, |scope, _loc, path| {
+        let resolved = match type_resolver.resolve_message_or_enum(scope, path) {
+            Ok(resolved) => resolved.full_name,
+            Err(_) => return Ok(()),
+        };
+        let mut changed = rename_reference(path, &resolved, from, to);
+        let expected = match resolved.remove_prefix(from) {
+            Some(rem) => renamed_full_name(from, to, rem),
+            None => resolved,
+        };
+        // Renaming can make a relative reference resolve to another type
+        let now = renamed_type_resolver
+            .resolve_message_or_enum(scope, path)
+            .ok()
+            .map(|r| r.full_name);
+        if now.as_ref() != Some(&expected) {
+            *path = ProtobufPath::Abs(expected);
+            changed = true;
+        }
+        if changed {
+            count += 1;
+        }
+        Ok(())
+    })?;
+
+    rename_declaration(&package, &mut file.messages, &mut file.enums, from, to);
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str) -> model::FileDescriptor {
+        model::FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn field_type(message: &model::Message, name: &str) -> String {
+        match &message.field_by_name(name).unwrap().typ {
+            model::FieldType::MessageOrEnum(path) => path.to_string(),
+            model::FieldType::Map(key_value) => match &key_value.1 {
+                model::FieldType::MessageOrEnum(path) => path.to_string(),
+                t => panic!("{:?}", t),
+            },
+            t => panic!("{:?}", t),
+        }
+    }
+
+    const FILE: &str = r#"
+        syntax = "proto3";
+        package p;
+        message Outer {
+            message Inner {
+                message Deeper {}
+                Inner self = 1;
+            }
+            Inner a = 1;
+            Outer.Inner b = 2;
+            .p.Outer.Inner c = 3;
+            Inner.Deeper d = 4;
+            map<string, Inner> e = 5;
+            ext.Inner f = 6;
+        }
+        message Other {}
+        extend Outer.Inner { int32 x = 100; }
+        service S { rpc M(Outer.Inner) returns (Outer); }
+    "#;
+
+    #[test]
+    fn test_rename_nested_type() {
+        let mut file = parse(FILE);
+        let count = file
+            .rename_type(
+                &ProtobufAbsPath::new(".p.Outer.Inner"),
+                &ProtobufIdent::new("Renamed"),
+            )
+            .unwrap();
+        assert_eq!(8, count);
+
+        let outer = &file.messages[0].t;
+        let renamed = &outer.messages[0].t;
+        assert_eq!("Renamed", renamed.name);
+        assert_eq!("Renamed", field_type(renamed, "self"));
+        assert_eq!("Renamed", field_type(outer, "a"));
+        assert_eq!("Outer.Renamed", field_type(outer, "b"));
+        assert_eq!(".p.Outer.Renamed", field_type(outer, "c"));
+        assert_eq!("Renamed.Deeper", field_type(outer, "d"));
+        assert_eq!("Renamed", field_type(outer, "e"));
+        assert_eq!("ext.Inner", field_type(outer, "f"));
+        assert_eq!("Outer.Renamed", file.extensions[0].t.extendee.to_string());
+        let method = &file.services[0].t.methods[0];
+        assert_eq!("Outer.Renamed", method.input_type.to_string());
+        assert_eq!("Outer", method.output_type.to_string());
+    }
+
+    #[test]
+    fn test_rename_unknown_type() {
+        let mut file = parse(FILE);
+        assert!(file
+            .rename_type(
+                &ProtobufAbsPath::new(".p.Missing"),
+                &ProtobufIdent::new("Renamed"),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_rename_to_existing_name() {
+        let mut file = parse(FILE);
+        let e = file
+            .rename_type(
+                &ProtobufAbsPath::new(".p.Outer"),
+                &ProtobufIdent::new("Other"),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(ParserError::TypeNameConflict(path)) if path.to_string() == ".p.Other"
+        ));
+    }
+
+    #[test]
+    fn test_rename_shadowing() {
+        // `Inner` in `A` would resolve to `.p.A.Renamed` instead of the renamed type
+        let mut file = parse(
+            "package p; message A { message Renamed {} optional Inner x = 1; } message Inner {}",
+        );
+        let count = file
+            .rename_type(
+                &ProtobufAbsPath::new(".p.Inner"),
+                &ProtobufIdent::new("Renamed"),
+            )
+            .unwrap();
+        assert_eq!(1, count);
+        assert_eq!(".p.Renamed", field_type(&file.messages[0].t, "x"));
+        assert_eq!("Renamed", file.messages[1].t.name);
+    }
+
+    #[test]
+    fn test_rename_shadows_other_reference() {
+        // New name `B` in `A` shadows `.p.B` referenced from `A`
+        let mut file = parse(
+            r#"
+            package p;
+            message A {
+                message Inner {}
+                optional B b = 1;
+                optional Inner i = 2;
+            }
+            message B {}
+            "#,
+        );
+        let count = file
+            .rename_type(
+                &ProtobufAbsPath::new(".p.A.Inner"),
+                &ProtobufIdent::new("B"),
+            )
+            .unwrap();
+        assert_eq!(2, count);
+        let a = &file.messages[0].t;
+        assert_eq!(".p.B", field_type(a, "b"));
+        assert_eq!("B", field_type(a, "i"));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::model;
+use crate::model::Loc;
+use crate::protobuf_abs_path::ProtobufAbsPathRef;
+use crate::protobuf_ident::ProtobufIdentRef;
+use crate::protobuf_path::ProtobufPath;
+
+/// Visits every type reference of a file together with the scope
+/// it is resolved in and the location of the declaration using it.
+struct TypeRefsMut<F> {
+    f: F,
+}
+
+impl<F> TypeRefsMut<F>
+where
+    F: FnMut(&ProtobufAbsPathRef, Loc, &mut ProtobufPath) -> anyhow::Result<()>,
+{
+    fn field_type(
+        &mut self,
+        scope: &ProtobufAbsPathRef,
+        loc: Loc,
+        field_type: &mut model::FieldType,
+    ) -> anyhow::Result<()> {
+        match field_type {
+            model::FieldType::MessageOrEnum(path) => (self.f)(scope, loc, path),
+            model::FieldType::Map(key_value) => {
+                self.field_type(scope, loc, &mut key_value.0)?;
+                self.field_type(scope, loc, &mut key_value.1)
+            }
+            model::FieldType::Group(group) => {
+                let mut group_scope = scope.to_owned();
+                group_scope.push_simple(ProtobufIdentRef::new(&group.name));
+                for f in &mut group.fields {
+                    self.field_type(&group_scope, f.loc, &mut f.t.typ)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn extension(
+        &mut self,
+        scope: &ProtobufAbsPathRef,
+        extension: &mut model::WithLoc<model::Extension>,
+    ) -> anyhow::Result<()> {
+        (self.f)(scope, extension.loc, &mut extension.t.extendee)?;
+        let field = &mut extension.t.field;
+        self.field_type(scope, field.loc, &mut field.t.typ)
+    }
+
+    fn message(
+        &mut self,
+        scope: &ProtobufAbsPathRef,
+        message: &mut model::Message,
+    ) -> anyhow::Result<()> {
+        let mut nested_scope = scope.to_owned();
+        nested_scope.push_simple(ProtobufIdentRef::new(&message.name));
+
+        for fo in &mut message.fields {
+            match &mut fo.t {
+                model::FieldOrOneOf::Field(f) => {
+                    self.field_type(&nested_scope, f.loc, &mut f.t.typ)?
+                }
+                model::FieldOrOneOf::OneOf(o) => {
+                    for f in &mut o.fields {
+                        self.field_type(&nested_scope, f.loc, &mut f.t.typ)?;
+                    }
+                }
+            }
+        }
+
+        for e in &mut message.extensions {
+            self.extension(&nested_scope, e)?;
+        }
+
+        for m in &mut message.messages {
+            self.message(&nested_scope, &mut m.t)?;
+        }
+
+        Ok(())
+    }
+
+    fn file(&mut self, file: &mut model::FileDescriptor) -> anyhow::Result<()> {
+        let package = file.package.clone();
+
+        for m in &mut file.messages {
+            self.message(&package, &mut m.t)?;
+        }
+
+        for e in &mut file.extensions {
+            self.extension(&package, e)?;
+        }
+
+        for s in &mut file.services {
+            for method in &mut s.t.methods {
+                (self.f)(&package, method.input_type_loc, &mut method.input_type)?;
+                (self.f)(&package, method.output_type_loc, &mut method.output_type)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Call `f` for field types, extendees and method input and output types
+/// of the file, with the scope the reference is resolved in and the location
+/// of the field, extension or method type.
+pub(crate) fn for_each_type_ref_mut(
+    file: &mut model::FileDescriptor,
+    f: impl FnMut(&ProtobufAbsPathRef, Loc, &mut ProtobufPath) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    TypeRefsMut { f }.file(file)
+}
@@ -0,0 +1,47 @@
+use protobuf_support::lexer::float;
+
+/// Format float as in protobuf `.proto` files
+pub use protobuf_support::lexer::float::format_protobuf_float;
+
+/// Parse float as written in protobuf `.proto` files, the inverse
+/// of [`format_protobuf_float`].
+///
+/// Accepts `inf`, `-inf`, `nan`, decimal and scientific notation.
+pub fn parse_protobuf_float(s: &str) -> Option<f64> {
+    float::parse_protobuf_float(s).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for f in [
+            0.0,
+            -1.5,
+            10.0,
+            1e100,
+            -2.5e-300,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert_eq!(Some(f), parse_protobuf_float(&format_protobuf_float(f)));
+        }
+        assert!(parse_protobuf_float(&format_protobuf_float(f64::NAN))
+            .unwrap()
+            .is_nan());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(f64::INFINITY), parse_protobuf_float("inf"));
+        assert_eq!(Some(f64::NEG_INFINITY), parse_protobuf_float("-inf"));
+        assert_eq!(Some(1.5e3), parse_protobuf_float("1.5e3"));
+        assert_eq!(Some(-2e-2), parse_protobuf_float("-2E-2"));
+        assert_eq!(None, parse_protobuf_float(""));
+        assert_eq!(None, parse_protobuf_float("1.5x"));
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::ops::RangeInclusive;
+use std::sync::Arc;
 
 use protobuf_support::lexer::{
     int,
     lexer_impl::LexerError,
+    loc::Loc,
     num_lit::NumLit,
     parser_language::ParserLanguage,
     str_lit::StrLitDecodeError,
@@ -12,25 +16,42 @@ use protobuf_support::lexer::{
 
 use model::{
     AnyTypeUrl, EnumValue, Enumeration, Extension, Field, FieldOrOneOf, FieldType, FileDescriptor,
-    Group, ImportVis, Message, Method, OneOf, ProtobufConstant, ProtobufConstantMessage,
+    Group, Import, ImportVis, Message, Method, OneOf, ProtobufConstant, ProtobufConstantMessage,
     ProtobufConstantMessageFieldName, ProtobufOption, ProtobufOptionName, ProtobufOptionNameExt,
-    ProtobufOptionNamePart, Rule, Service, WithLoc,
+    ProtobufOptionNamePart, Rule, Service, Span, WithLoc,
 };
 use proto_path::ProtoPathBuf;
 use protobuf_abs_path::ProtobufAbsPath;
 use protobuf_ident::ProtobufIdent;
 use protobuf_path::ProtobufPath;
 use protobuf_rel_path::ProtobufRelPath;
+use span::LineIndex;
+use str_lit::StrLitEx;
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
 pub mod case_convert;
+pub mod comments;
 pub mod convert;
+pub mod float;
+pub mod lint;
 pub mod model;
 pub mod path;
+mod printer;
 pub mod proto_path;
 pub mod protobuf_abs_path;
 pub mod protobuf_ident;
 pub mod protobuf_path;
 pub mod protobuf_rel_path;
+mod span;
+pub mod str_lit;
+mod utf8;
+
+/// Greatest field number, written as `max` in ranges
+pub(crate) const MAX_FIELD_NUMBER: i32 = 0x20000000 - 1;
+
+/// Nesting limit of message bodies and message constants, combined
+const MAX_RECURSION_DEPTH: u32 = 100;
 
 #[derive(Clone)]
 pub struct FileDescriptorPair {
@@ -62,6 +83,10 @@ pub(crate) enum ParserError {
     ExpectConstant,
     #[error("unknown syntax")]
     UnknownSyntax,
+    #[error("`syntax` must be the first statement of the file and appear only once")]
+    MisplacedSyntax,
+    #[error("unexpected `{found}` after {context}")]
+    TrailingInput { found: String, context: String },
     #[error("integer overflow")]
     IntegerOverflow,
     #[error("label not allowed")]
@@ -82,6 +107,28 @@ pub(crate) enum ParserError {
     OneOfInOneOf,
     #[error("oneof in extend")]
     OneOfInExtend,
+    #[error("enum value `{0}` uses a reserved number or name")]
+    EnumValueUsesReserved(String),
+    #[error("cannot resolve `{0}` in scope `{1}`")]
+    UnresolvedLocalName(ProtobufRelPath, ProtobufAbsPath),
+    #[error("unknown type `{0}` in scope `{1}`")]
+    UnknownType(ProtobufPath, ProtobufAbsPath),
+    #[error("type `{0}` already exists")]
+    TypeNameConflict(ProtobufAbsPath),
+    #[error("range bound {0} is greater than {1}")]
+    RangeBoundOutOfRange(u64, i32),
+    #[error("invalid UTF-8 in string literal")]
+    InvalidUtf8,
+    #[error("rpc input and output types must be messages, `{0}` is not a message")]
+    RpcTypeMustBeMessage(String),
+    #[error("fields `{a}` and `{b}` have the same JSON name")]
+    JsonNameConflict { a: String, b: String },
+    #[error("invalid JSON name {0:?}, it must be non-empty without control characters")]
+    InvalidJsonName(String),
+    #[error("nesting is deeper than {MAX_RECURSION_DEPTH}")]
+    RecursionLimitExceeded,
+    #[error("field number {0} is greater than {1}")]
+    FieldNumberOutOfRange(i32, i32),
 }
 
 impl From<TokenizerError> for ParserError {
@@ -108,6 +155,15 @@ impl From<int::Overflow> for ParserError {
     }
 }
 
+/// Error about a declaration found after the declaration is parsed,
+/// located at the declaration rather than at the current token
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub(crate) struct ParserErrorAt {
+    pub(crate) error: ParserError,
+    pub(crate) loc: Loc,
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("at {line}:{col}: {error}")]
 pub struct ParserErrorWithLocation {
@@ -119,6 +175,57 @@ pub struct ParserErrorWithLocation {
     pub col: u32,
 }
 
+impl ParserErrorWithLocation {
+    pub(crate) fn new(error: anyhow::Error, tokenizer: &Tokenizer) -> ParserErrorWithLocation {
+        let (error, Loc { line, col }) = match error.downcast::<ParserErrorAt>() {
+            Ok(ParserErrorAt { error, loc }) => (error.into(), loc),
+            Err(error) => (error, tokenizer.loc()),
+        };
+        // Lexer fails to parse integer literals which do not fit into `u64`,
+        // the location is that of the preceding token.
+        let error = match error.downcast_ref::<TokenizerError>() {
+            Some(TokenizerError::LexerError(LexerError::ParseIntError)) => {
+                ParserError::IntegerOverflow.into()
+            }
+            _ => error,
+        };
+        ParserErrorWithLocation { error, line, col }
+    }
+}
+
+/// Non-fatal issue found while parsing
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ParserWarning {
+    #[error("import `{0}` follows a type declaration")]
+    LateImport(ProtoPathBuf),
+    #[error("missing `;` after option `{0}`")]
+    MissingOptionSemicolon(String),
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("at {line}:{col}: {warning}")]
+pub struct ParserWarningWithLocation {
+    pub warning: ParserWarning,
+    /// 1-based
+    pub line: u32,
+    /// 1-based
+    pub col: u32,
+}
+
+/// Top-level statement of a `.proto` file
+#[derive(Debug, Clone)]
+pub enum TopLevel {
+    Syntax(Syntax),
+    Import(Import),
+    Package(ProtobufAbsPath),
+    Option(ProtobufOption),
+    Message(WithLoc<Message>),
+    Enum(WithLoc<Enumeration>),
+    /// Fields of one `extend` statement
+    Extensions(Vec<WithLoc<Extension>>),
+    Service(WithLoc<Service>),
+}
+
 #[derive(Copy, Clone)]
 enum MessageBodyParseMode {
     MessageProto2,
@@ -143,9 +250,12 @@ impl MessageBodyParseMode {
                 MessageBodyParseMode::MessageProto3 | MessageBodyParseMode::ExtendProto3 => true,
                 MessageBodyParseMode::Oneof => false,
             },
+            // protoc rejects required extensions
             Rule::Required => match *self {
-                MessageBodyParseMode::MessageProto2 | MessageBodyParseMode::ExtendProto2 => true,
-                MessageBodyParseMode::MessageProto3 | MessageBodyParseMode::ExtendProto3 => false,
+                MessageBodyParseMode::MessageProto2 => true,
+                MessageBodyParseMode::MessageProto3
+                | MessageBodyParseMode::ExtendProto2
+                | MessageBodyParseMode::ExtendProto3 => false,
                 MessageBodyParseMode::Oneof => false,
             },
         }
@@ -193,8 +303,9 @@ impl MessageBodyParseMode {
     }
 }
 
-#[derive(Default)]
-pub(crate) struct MessageBody {
+/// Declarations of a message body, see [`Parser::parse_message_body`]
+#[derive(Debug, Clone, Default)]
+pub struct MessageBody {
     pub fields: Vec<WithLoc<FieldOrOneOf>>,
     pub reserved_nums: Vec<RangeInclusive<i32>>,
     pub reserved_names: Vec<String>,
@@ -205,6 +316,31 @@ pub(crate) struct MessageBody {
     pub extensions: Vec<WithLoc<Extension>>,
 }
 
+impl MessageBody {
+    /// Message with given name and this body
+    pub fn into_message(self, name: String) -> Message {
+        Message {
+            name,
+            fields: self.fields,
+            reserved_nums: self.reserved_nums,
+            reserved_names: self.reserved_names,
+            messages: self.messages,
+            enums: self.enums,
+            options: self.options,
+            extensions: self.extensions,
+            extension_ranges: self.extension_ranges,
+        }
+    }
+}
+
+// Extension range as declared, checked by `Parser::resolve_extension_ranges`
+// once it is known whether the message is a MessageSet
+struct ExtensionRangeDecl {
+    start: WithLoc<u64>,
+    // `None` is `max`
+    end: Option<WithLoc<u64>>,
+}
+
 trait ToI32 {
     fn to_i32(&self) -> anyhow::Result<i32>;
 }
@@ -246,9 +382,25 @@ impl ToI64 for u64 {
 #[derive(Clone)]
 pub struct Parser<'a> {
     pub tokenizer: Tokenizer<'a>,
+    input: &'a str,
+    /// Shared by clones of the parser
+    line_index: Arc<LineIndex>,
     syntax: Syntax,
+    resolve_local_names: bool,
+    /// Types declared in other files, shared by clones of the parser
+    known_types: Option<Arc<HashSet<ProtobufAbsPath>>>,
+    lenient: bool,
+    /// Message bodies and message constants being parsed
+    depth: u32,
+    warnings: Vec<ParserWarningWithLocation>,
 }
 
+// Parser must stay `Send`, e. g. to parse files on a thread pool
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Parser>();
+};
+
 trait NumLitEx {
     fn to_option_value(&self, sign_is_plus: bool) -> anyhow::Result<ProtobufConstant>;
 }
@@ -266,12 +418,152 @@ impl NumLitEx for NumLit {
     }
 }
 
+trait TokenizerEx {
+    /// Fail if any tokens remain, `context` names what was parsed
+    fn expect_eof(&mut self, context: &str) -> anyhow::Result<()>;
+}
+
+impl<'a> TokenizerEx for Tokenizer<'a> {
+    fn expect_eof(&mut self, context: &str) -> anyhow::Result<()> {
+        if self.syntax_eof()? {
+            return Ok(());
+        }
+        Err(ParserError::TrailingInput {
+            found: self.lookahead_some()?.format(),
+            context: context.to_owned(),
+        }
+        .into())
+    }
+}
+
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Parser<'a> {
         Parser {
             tokenizer: Tokenizer::new(input, ParserLanguage::Proto),
+            input,
+            line_index: Arc::new(LineIndex::new(input)),
             syntax: Syntax::Proto2,
+            resolve_local_names: false,
+            known_types: None,
+            lenient: false,
+            depth: 0,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Resolve relative type references to types declared in the parsed file
+    /// to absolute paths once parsing completes.
+    ///
+    /// Names which are not declared in the file are left relative.
+    pub fn resolve_local_names(&mut self, resolve: bool) -> &mut Self {
+        self.resolve_local_names = resolve;
+        self
+    }
+
+    /// Types declared in other files, e. g. `.foo.Bar`.
+    ///
+    /// All type references are resolved to absolute paths of types declared
+    /// in the parsed file or given here, a reference to any other type is an error.
+    pub fn with_known_types(&mut self, types: HashSet<ProtobufAbsPath>) -> &mut Self {
+        self.known_types = Some(Arc::new(types));
+        self
+    }
+
+    /// Accept quirks of some `.proto` emitters, reporting them as warnings:
+    /// a missing `;` after an option followed by `}` or another declaration.
+    pub fn lenient(&mut self, lenient: bool) -> &mut Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Warnings reported so far
+    pub fn warnings(&self) -> &[ParserWarningWithLocation] {
+        &self.warnings
+    }
+
+    // Parse a nested message body or message constant with `f`
+    fn nested<R>(&mut self, f: impl FnOnce(&mut Self) -> anyhow::Result<R>) -> anyhow::Result<R> {
+        if self.depth >= MAX_RECURSION_DEPTH {
+            return Err(ParserError::RecursionLimitExceeded.into());
         }
+        self.depth += 1;
+        let r = f(self);
+        self.depth -= 1;
+        r
+    }
+
+    fn warn(&mut self, warning: ParserWarning, loc: Loc) {
+        let Loc { line, col } = loc;
+        self.warnings
+            .push(ParserWarningWithLocation { warning, line, col });
+    }
+
+    /// Parses concatenated `.proto` files. Each `syntax` statement
+    /// starts a new file, so syntax and package are reset.
+    ///
+    /// Empty input yields no files.
+    pub fn parse_all(input: &str) -> Result<Vec<FileDescriptor>, ParserErrorWithLocation> {
+        let mut parser = Parser::new(input);
+        match parser.next_files() {
+            Ok(r) => Ok(r),
+            Err(error) => Err(ParserErrorWithLocation::new(error, &parser.tokenizer)),
+        }
+    }
+
+    /// Parses a standalone text format message, e. g. the content of a
+    /// `.txtpb` data file. The message is not surrounded by `{` and `}`.
+    pub fn parse_text_message(
+        input: &str,
+    ) -> Result<ProtobufConstantMessage, ParserErrorWithLocation> {
+        let mut parser = Parser {
+            tokenizer: Tokenizer::new(input, ParserLanguage::TextFormat),
+            input,
+            line_index: Arc::new(LineIndex::new(input)),
+            syntax: Syntax::Proto2,
+            resolve_local_names: false,
+            known_types: None,
+            lenient: false,
+            depth: 0,
+            warnings: Vec::new(),
+        };
+        match parser.next_text_message() {
+            Ok(r) => Ok(r),
+            Err(error) => Err(ParserErrorWithLocation::new(error, &parser.tokenizer)),
+        }
+    }
+
+    /// Parses declarations of a message body without the enclosing
+    /// `message Name { ... }`, e. g. an editor snippet.
+    pub fn parse_message_body(
+        input: &str,
+        syntax: Syntax,
+    ) -> Result<MessageBody, ParserErrorWithLocation> {
+        let mut parser = Parser::new(input);
+        parser.syntax = syntax;
+        match parser.next_message_body_fragment() {
+            Ok(r) => Ok(r),
+            Err(error) => Err(ParserErrorWithLocation::new(error, &parser.tokenizer)),
+        }
+    }
+
+    /// Parses a standalone constant, e. g. an option value.
+    pub fn parse_constant(input: &str) -> Result<ProtobufConstant, ParserErrorWithLocation> {
+        let mut parser = Parser::new(input);
+        let r = parser.next_constant().and_then(|r| {
+            parser.tokenizer.expect_eof("constant")?;
+            Ok(r)
+        });
+        r.map_err(|error| ParserErrorWithLocation::new(error, &parser.tokenizer))
+    }
+
+    /// Parses a standalone field type, e. g. `map<string, int32>`.
+    pub fn parse_field_type(input: &str) -> Result<FieldType, ParserErrorWithLocation> {
+        let mut parser = Parser::new(input);
+        let r = parser.next_field_type().and_then(|r| {
+            parser.tokenizer.expect_eof("field type")?;
+            Ok(r)
+        });
+        r.map_err(|error| ParserErrorWithLocation::new(error, &parser.tokenizer))
     }
 
     // Protobuf grammar
@@ -319,6 +611,17 @@ impl<'a> Parser<'a> {
         self.next_full_ident()
     }
 
+    // Input or output type of rpc, which is a messageType.
+    // Enums are detected once the file is parsed, see `check_method_types`.
+    fn next_rpc_type(&mut self) -> anyhow::Result<ProtobufPath> {
+        // Leave a scalar type in lookahead so the error points to it
+        let token = self.tokenizer.lookahead_some()?;
+        if Parser::scalar_field_type(token).is_some() {
+            return Err(ParserError::RpcTypeMustBeMessage(token.format()).into());
+        }
+        self.next_message_or_enum_type()
+    }
+
     // groupName = capitalLetter { letter | decimalDigit | "_" }
     fn next_group_name(&mut self) -> anyhow::Result<String> {
         // lexer cannot distinguish between group name and other ident
@@ -334,6 +637,7 @@ impl<'a> Parser<'a> {
     // Boolean
 
     // boolLit = "true" | "false"
+    // Literals are case-sensitive: `True` or `FALSE` is parsed as an identifier.
     fn next_bool_lit_opt(&mut self) -> anyhow::Result<Option<bool>> {
         Ok(if self.tokenizer.next_ident_if_eq("true")? {
             Some(true)
@@ -376,17 +680,45 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Text format allows fields to be separated by "," or ";"
+    fn next_message_constant_field(
+        &mut self,
+        message: &mut ProtobufConstantMessage,
+    ) -> anyhow::Result<()> {
+        let n = self.next_message_constant_field_name()?;
+        let v = self.next_field_value()?;
+        message.add_field(n, v);
+        self.tokenizer.next_symbol_if_in(&[',', ';'])?;
+        Ok(())
+    }
+
+    // Comments between fields of an aggregate constant are dropped by the lexer
+    // (`//` and `/* */` in `.proto`, `#` in text format), so they never reach
+    // this function.
     fn next_message_constant(&mut self) -> anyhow::Result<ProtobufConstantMessage> {
+        self.nested(|p| {
+            let mut r = ProtobufConstantMessage::default();
+            // Text format also allows `<` and `>` as message delimiters.
+            let end = if p.tokenizer.next_symbol_if_eq('<')? {
+                '>'
+            } else {
+                p.tokenizer.next_symbol_expect_eq('{', "message constant")?;
+                '}'
+            };
+            while !p.tokenizer.lookahead_is_symbol(end)? {
+                p.next_message_constant_field(&mut r)?;
+            }
+            p.tokenizer.next_symbol_expect_eq(end, "message constant")?;
+            Ok(r)
+        })
+    }
+
+    // Top-level text format message, without the surrounding "{" and "}"
+    fn next_text_message(&mut self) -> anyhow::Result<ProtobufConstantMessage> {
         let mut r = ProtobufConstantMessage::default();
-        self.tokenizer
-            .next_symbol_expect_eq('{', "message constant")?;
-        while !self.tokenizer.lookahead_is_symbol('}')? {
-            let n = self.next_message_constant_field_name()?;
-            let v = self.next_field_value()?;
-            r.fields.insert(n, v);
+        while !self.tokenizer.syntax_eof()? {
+            self.next_message_constant_field(&mut r)?;
         }
-        self.tokenizer
-            .next_symbol_expect_eq('}', "message constant")?;
         Ok(r)
     }
 
@@ -430,15 +762,46 @@ impl<'a> Parser<'a> {
         Err(ParserError::ExpectConstant.into())
     }
 
-    fn next_field_value(&mut self) -> anyhow::Result<ProtobufConstant> {
-        if self.tokenizer.next_symbol_if_eq(':')? {
-            // Colon is optional when reading message constant.
+    fn next_constant_with_span(&mut self) -> anyhow::Result<(ProtobufConstant, Option<Span>)> {
+        let start = self.tokenizer.lookahead_loc();
+        let value = self.next_constant()?;
+        let stop = self.tokenizer.lookahead_loc();
+        let span = span::tokens_span(self.input, &self.line_index, start, stop);
+        Ok((value, span))
+    }
+
+    // "[" [ value { "," value } ] "]"
+    fn next_list_constant(&mut self, colon: bool) -> anyhow::Result<ProtobufConstant> {
+        let mut values = Vec::new();
+        self.tokenizer.next_symbol_expect_eq('[', "list constant")?;
+        if !self.tokenizer.lookahead_is_symbol(']')? {
+            values.push(self.next_list_element(colon)?);
+            while self.tokenizer.next_symbol_if_eq(',')? {
+                values.push(self.next_list_element(colon)?);
+            }
+        }
+        self.tokenizer.next_symbol_expect_eq(']', "list constant")?;
+        Ok(ProtobufConstant::Repeated(values))
+    }
+
+    fn next_list_element(&mut self, colon: bool) -> anyhow::Result<ProtobufConstant> {
+        if colon && !self.tokenizer.lookahead_is_symbol('<')? {
             self.next_constant()
         } else {
             Ok(ProtobufConstant::Message(self.next_message_constant()?))
         }
     }
 
+    fn next_field_value(&mut self) -> anyhow::Result<ProtobufConstant> {
+        // Colon is optional when reading message constant.
+        let colon = self.tokenizer.next_symbol_if_eq(':')?;
+        if self.tokenizer.lookahead_is_symbol('[')? {
+            self.next_list_constant(colon)
+        } else {
+            self.next_list_element(colon)
+        }
+    }
+
     fn next_int_lit(&mut self) -> anyhow::Result<u64> {
         self.tokenizer.next_token_check_map(|token| match token {
             &Token::IntLit(i) => Ok(i),
@@ -453,7 +816,7 @@ impl<'a> Parser<'a> {
     fn next_syntax(&mut self) -> anyhow::Result<Option<Syntax>> {
         if self.tokenizer.next_ident_if_eq("syntax")? {
             self.tokenizer.next_symbol_expect_eq('=', "syntax")?;
-            let syntax_str = self.tokenizer.next_str_lit()?.decode_utf8()?;
+            let syntax_str = self.tokenizer.next_str_lit()?.decode_proto_utf8()?;
             let syntax = if syntax_str == "proto2" {
                 Syntax::Proto2
             } else if syntax_str == "proto3" {
@@ -480,7 +843,7 @@ impl<'a> Parser<'a> {
             } else {
                 ImportVis::Default
             };
-            let path = self.tokenizer.next_str_lit()?.decode_utf8()?;
+            let path = self.tokenizer.next_str_lit()?.decode_proto_utf8()?;
             self.tokenizer.next_symbol_expect_eq(';', "import")?;
             let path = ProtoPathBuf::new(path)?;
             Ok(Some(model::Import { path, vis }))
@@ -536,18 +899,44 @@ impl<'a> Parser<'a> {
     }
 
     // option = "option" optionName  "=" constant ";"
+    // protoc requires "=" even before an aggregate value, so `option (x) { ... };`
+    // is rejected. Only fields inside an aggregate may omit ":" before "{".
     fn next_option_opt(&mut self) -> anyhow::Result<Option<ProtobufOption>> {
+        let loc = self.tokenizer.lookahead_loc();
         if self.tokenizer.next_ident_if_eq("option")? {
             let name = self.next_option_name()?;
             self.tokenizer.next_symbol_expect_eq('=', "option")?;
-            let value = self.next_constant()?;
-            self.tokenizer.next_symbol_expect_eq(';', "option")?;
-            Ok(Some(ProtobufOption { name, value }))
+            let (value, value_span) = self.next_constant_with_span()?;
+            self.next_option_end(&name)?;
+            Ok(Some(ProtobufOption {
+                name,
+                value,
+                value_span,
+                loc,
+            }))
         } else {
             Ok(None)
         }
     }
 
+    // `;` ending an option, which lenient parser allows to omit
+    // before `}` or a declaration
+    fn next_option_end(&mut self, name: &ProtobufOptionName) -> anyhow::Result<()> {
+        if self.lenient
+            && !self.tokenizer.syntax_eof()?
+            && matches!(
+                self.tokenizer.lookahead_some()?,
+                Token::Symbol('}') | Token::Ident(..)
+            )
+        {
+            let loc = self.tokenizer.lookahead_loc();
+            self.warn(ParserWarning::MissingOptionSemicolon(name.to_string()), loc);
+            return Ok(());
+        }
+        self.tokenizer.next_symbol_expect_eq(';', "option")?;
+        Ok(())
+    }
+
     // Fields
 
     // label = "required" | "optional" | "repeated"
@@ -571,28 +960,38 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Scalar type named by `token`
+    fn scalar_field_type(token: &Token) -> Option<FieldType> {
+        let name = match token {
+            Token::Ident(name) => name.as_str(),
+            _ => return None,
+        };
+        Some(match name {
+            "int32" => FieldType::Int32,
+            "int64" => FieldType::Int64,
+            "uint32" => FieldType::Uint32,
+            "uint64" => FieldType::Uint64,
+            "sint32" => FieldType::Sint32,
+            "sint64" => FieldType::Sint64,
+            "fixed32" => FieldType::Fixed32,
+            "sfixed32" => FieldType::Sfixed32,
+            "fixed64" => FieldType::Fixed64,
+            "sfixed64" => FieldType::Sfixed64,
+            "bool" => FieldType::Bool,
+            "string" => FieldType::String,
+            "bytes" => FieldType::Bytes,
+            "float" => FieldType::Float,
+            "double" => FieldType::Double,
+            _ => return None,
+        })
+    }
+
     fn next_field_type(&mut self) -> anyhow::Result<FieldType> {
-        let simple = &[
-            ("int32", FieldType::Int32),
-            ("int64", FieldType::Int64),
-            ("uint32", FieldType::Uint32),
-            ("uint64", FieldType::Uint64),
-            ("sint32", FieldType::Sint32),
-            ("sint64", FieldType::Sint64),
-            ("fixed32", FieldType::Fixed32),
-            ("sfixed32", FieldType::Sfixed32),
-            ("fixed64", FieldType::Fixed64),
-            ("sfixed64", FieldType::Sfixed64),
-            ("bool", FieldType::Bool),
-            ("string", FieldType::String),
-            ("bytes", FieldType::Bytes),
-            ("float", FieldType::Float),
-            ("double", FieldType::Double),
-        ];
-        for (n, ref t) in simple {
-            if self.tokenizer.next_ident_if_eq(n)? {
-                return Ok(t.clone());
-            }
+        if let Some(t) = self
+            .tokenizer
+            .next_token_if_map(Parser::scalar_field_type)?
+        {
+            return Ok(t);
         }
 
         if let Some(t) = self.next_map_field_type_opt()? {
@@ -613,10 +1012,16 @@ impl<'a> Parser<'a> {
 
     // fieldOption = optionName "=" constant
     fn next_field_option(&mut self) -> anyhow::Result<ProtobufOption> {
+        let loc = self.tokenizer.lookahead_loc();
         let name = self.next_option_name()?;
         self.tokenizer.next_symbol_expect_eq('=', "field option")?;
-        let value = self.next_constant()?;
-        Ok(ProtobufOption { name, value })
+        let (value, value_span) = self.next_constant_with_span()?;
+        Ok(ProtobufOption {
+            name,
+            value,
+            value_span,
+            loc,
+        })
     }
 
     // fieldOptions = fieldOption { ","  fieldOption }
@@ -697,6 +1102,19 @@ impl<'a> Parser<'a> {
                 number,
                 options,
             };
+            // Validate `json_name` option, located at the option
+            if let Err(e) = field.json_name() {
+                let json_name_option = ProtobufOptionName::simple("json_name");
+                let loc = field
+                    .options
+                    .iter()
+                    .find(|o| o.name == json_name_option)
+                    .map_or(loc, |o| o.loc);
+                return Err(match e.downcast::<ParserError>() {
+                    Ok(error) => ParserErrorAt { error, loc }.into(),
+                    Err(e) => e,
+                });
+            }
             Ok(WithLoc { t: field, loc })
         }
     }
@@ -729,6 +1147,8 @@ impl<'a> Parser<'a> {
     // mapField = "map" "<" keyType "," type ">" mapName "=" fieldNumber [ "[" fieldOptions "]" ] ";"
     // keyType = "int32" | "int64" | "uint32" | "uint64" | "sint32" | "sint64" |
     //           "fixed32" | "fixed64" | "sfixed32" | "sfixed64" | "bool" | "string"
+    // Value `type` may be a fully-qualified `messageType` with a leading ".",
+    // which is kept as `ProtobufPath::Abs`.
     fn next_map_field_type_opt(&mut self) -> anyhow::Result<Option<FieldType>> {
         if self.tokenizer.next_ident_if_eq("map")? {
             self.tokenizer
@@ -750,14 +1170,23 @@ impl<'a> Parser<'a> {
 
     // Extensions
 
+    fn next_range_bound(&mut self, max: i32) -> anyhow::Result<i32> {
+        let bound = self.next_int_lit()?;
+        if bound > max as u64 {
+            return Err(ParserError::RangeBoundOutOfRange(bound, max).into());
+        }
+        Ok(bound as i32)
+    }
+
     // range =  intLit [ "to" ( intLit | "max" ) ]
-    fn next_range(&mut self) -> anyhow::Result<RangeInclusive<i32>> {
-        let from = self.next_field_number()?;
+    // Bounds must not be greater than `max`, which is also the value of `max` keyword.
+    fn next_range(&mut self, max: i32) -> anyhow::Result<RangeInclusive<i32>> {
+        let from = self.next_range_bound(max)?;
         let to = if self.tokenizer.next_ident_if_eq("to")? {
             if self.tokenizer.next_ident_if_eq("max")? {
-                0x20000000 - 1
+                max
             } else {
-                self.next_field_number()?
+                self.next_range_bound(max)?
             }
         } else {
             from
@@ -766,19 +1195,47 @@ impl<'a> Parser<'a> {
     }
 
     // ranges = range { "," range }
-    fn next_ranges(&mut self) -> anyhow::Result<Vec<RangeInclusive<i32>>> {
+    fn next_ranges(&mut self, max: i32) -> anyhow::Result<Vec<RangeInclusive<i32>>> {
         let mut ranges = Vec::new();
-        ranges.push(self.next_range()?);
+        ranges.push(self.next_range(max)?);
         while self.tokenizer.next_symbol_if_eq(',')? {
-            ranges.push(self.next_range()?);
+            ranges.push(self.next_range(max)?);
         }
         Ok(ranges)
     }
 
+    fn next_extension_range_bound(&mut self) -> anyhow::Result<WithLoc<u64>> {
+        let loc = self.tokenizer.lookahead_loc();
+        let bound = self.next_int_lit()?;
+        Ok(WithLoc { t: bound, loc })
+    }
+
+    // range =  intLit [ "to" ( intLit | "max" ) ]
+    fn next_extension_range(&mut self) -> anyhow::Result<ExtensionRangeDecl> {
+        let start = self.next_extension_range_bound()?;
+        let end = if self.tokenizer.next_ident_if_eq("to")? {
+            if self.tokenizer.next_ident_if_eq("max")? {
+                None
+            } else {
+                Some(self.next_extension_range_bound()?)
+            }
+        } else {
+            Some(start.clone())
+        };
+        Ok(ExtensionRangeDecl { start, end })
+    }
+
     // extensions = "extensions" ranges ";"
-    fn next_extensions_opt(&mut self) -> anyhow::Result<Option<Vec<RangeInclusive<i32>>>> {
+    //
+    // Whether the message is a MessageSet is only known once it is parsed,
+    // so bounds are checked by `resolve_extension_ranges`.
+    fn next_extensions_opt(&mut self) -> anyhow::Result<Option<Vec<ExtensionRangeDecl>>> {
         if self.tokenizer.next_ident_if_eq("extensions")? {
-            Ok(Some(self.next_ranges()?))
+            let mut ranges = vec![self.next_extension_range()?];
+            while self.tokenizer.next_symbol_if_eq(',')? {
+                ranges.push(self.next_extension_range()?);
+            }
+            Ok(Some(ranges))
         } else {
             Ok(None)
         }
@@ -789,19 +1246,22 @@ impl<'a> Parser<'a> {
     // Grammar is incorrect: https://github.com/google/protobuf/issues/4558
     // reserved = "reserved" ( ranges | fieldNames ) ";"
     // fieldNames = fieldName { "," fieldName }
+    //
+    // `max` is the greatest reserved number allowed.
     fn next_reserved_opt(
         &mut self,
+        max: i32,
     ) -> anyhow::Result<Option<(Vec<RangeInclusive<i32>>, Vec<String>)>> {
         if self.tokenizer.next_ident_if_eq("reserved")? {
             let (ranges, names) = if let &Token::StrLit(..) = self.tokenizer.lookahead_some()? {
                 let mut names = Vec::new();
-                names.push(self.tokenizer.next_str_lit()?.decode_utf8()?);
+                names.push(self.tokenizer.next_str_lit()?.decode_proto_utf8()?);
                 while self.tokenizer.next_symbol_if_eq(',')? {
-                    names.push(self.tokenizer.next_str_lit()?.decode_utf8()?);
+                    names.push(self.tokenizer.next_str_lit()?.decode_proto_utf8()?);
                 }
                 (Vec::new(), names)
             } else {
-                (self.next_ranges()?, Vec::new())
+                (self.next_ranges(max)?, Vec::new())
             };
 
             self.tokenizer.next_symbol_expect_eq(';', "reserved")?;
@@ -818,11 +1278,17 @@ impl<'a> Parser<'a> {
 
     // enumValueOption = optionName "=" constant
     fn next_enum_value_option(&mut self) -> anyhow::Result<ProtobufOption> {
+        let loc = self.tokenizer.lookahead_loc();
         let name = self.next_option_name()?;
         self.tokenizer
             .next_symbol_expect_eq('=', "enum value option")?;
-        let value = self.next_constant()?;
-        Ok(ProtobufOption { name, value })
+        let (value, value_span) = self.next_constant_with_span()?;
+        Ok(ProtobufOption {
+            name,
+            value,
+            value_span,
+            loc,
+        })
     }
 
     // https://github.com/google/protobuf/issues/4561
@@ -842,6 +1308,7 @@ impl<'a> Parser<'a> {
 
     // enumField = ident "=" intLit [ "[" enumValueOption { ","  enumValueOption } "]" ]";"
     fn next_enum_field(&mut self) -> anyhow::Result<EnumValue> {
+        let loc = self.tokenizer.lookahead_loc();
         let name = self.tokenizer.next_ident()?.to_owned();
         self.tokenizer.next_symbol_expect_eq('=', "enum field")?;
         let number = self.next_enum_value()?;
@@ -858,6 +1325,7 @@ impl<'a> Parser<'a> {
             name,
             number,
             options,
+            loc,
         })
     }
 
@@ -870,6 +1338,7 @@ impl<'a> Parser<'a> {
             let name = self.tokenizer.next_ident()?.to_owned();
 
             let mut values = Vec::new();
+            let mut value_locs = Vec::new();
             let mut options = Vec::new();
             let mut reserved_nums = Vec::new();
             let mut reserved_names = Vec::new();
@@ -881,7 +1350,7 @@ impl<'a> Parser<'a> {
                     continue;
                 }
 
-                if let Some((field_nums, field_names)) = self.next_reserved_opt()? {
+                if let Some((field_nums, field_names)) = self.next_reserved_opt(i32::MAX)? {
                     reserved_nums.extend(field_nums);
                     reserved_names.extend(field_names);
                     continue;
@@ -892,9 +1361,20 @@ impl<'a> Parser<'a> {
                     continue;
                 }
 
+                value_locs.push(self.tokenizer.lookahead_loc());
                 values.push(self.next_enum_field()?);
             }
             self.tokenizer.next_symbol_expect_eq('}', "enum")?;
+
+            for (value, &loc) in values.iter().zip(&value_locs) {
+                if reserved_nums.iter().any(|r| r.contains(&value.number))
+                    || reserved_names.contains(&value.name)
+                {
+                    let error = ParserError::EnumValueUsesReserved(value.name.clone());
+                    return Err(ParserErrorAt { error, loc }.into());
+                }
+            }
+
             let enumeration = Enumeration {
                 name,
                 values,
@@ -916,80 +1396,175 @@ impl<'a> Parser<'a> {
     // messageBody = "{" { field | enum | message | extend | extensions | group |
     //               option | oneof | mapField | reserved | emptyStatement } "}"
     fn next_message_body(&mut self, mode: MessageBodyParseMode) -> anyhow::Result<MessageBody> {
-        self.tokenizer.next_symbol_expect_eq('{', "message body")?;
-
-        let mut r = MessageBody::default();
+        self.nested(|p| {
+            p.tokenizer.next_symbol_expect_eq('{', "message body")?;
 
-        while self.tokenizer.lookahead_if_symbol()? != Some('}') {
-            let loc = self.tokenizer.lookahead_loc();
+            let mut r = MessageBody::default();
+            let mut extension_ranges = Vec::new();
 
-            // emptyStatement
-            if self.tokenizer.next_symbol_if_eq(';')? {
-                continue;
+            while p.tokenizer.lookahead_if_symbol()? != Some('}') {
+                p.next_message_body_member(mode, &mut r, &mut extension_ranges)?;
             }
 
-            if mode.is_most_non_fields_allowed() {
-                if let Some((field_nums, field_names)) = self.next_reserved_opt()? {
-                    r.reserved_nums.extend(field_nums);
-                    r.reserved_names.extend(field_names);
-                    continue;
-                }
+            p.tokenizer.next_symbol_expect_eq('}', "message body")?;
 
-                if let Some(oneof) = self.next_oneof_opt()? {
-                    let one_of = FieldOrOneOf::OneOf(oneof);
-                    r.fields.push(WithLoc { t: one_of, loc });
-                    continue;
-                }
+            Parser::resolve_extension_ranges(&mut r, extension_ranges)?;
+            Ok(r)
+        })
+    }
 
-                if let Some(extensions) = self.next_extend_opt()? {
-                    r.extensions.extend(extensions);
-                    continue;
-                }
+    // Member of a message body, including emptyStatement
+    fn next_message_body_member(
+        &mut self,
+        mode: MessageBodyParseMode,
+        r: &mut MessageBody,
+        extension_ranges: &mut Vec<ExtensionRangeDecl>,
+    ) -> anyhow::Result<()> {
+        let loc = self.tokenizer.lookahead_loc();
 
-                if let Some(nested_message) = self.next_message_opt()? {
-                    r.messages.push(nested_message);
-                    continue;
-                }
+        // emptyStatement, also `;` after a nested block
+        if self.tokenizer.next_symbol_if_eq(';')? {
+            return Ok(());
+        }
 
-                if let Some(nested_enum) = self.next_enum_opt()? {
-                    r.enums.push(nested_enum);
-                    continue;
-                }
-            } else {
-                self.tokenizer.next_ident_if_eq_error("reserved")?;
-                self.tokenizer.next_ident_if_eq_error("oneof")?;
-                self.tokenizer.next_ident_if_eq_error("extend")?;
-                self.tokenizer.next_ident_if_eq_error("message")?;
-                self.tokenizer.next_ident_if_eq_error("enum")?;
+        if mode.is_most_non_fields_allowed() {
+            if let Some((field_nums, field_names)) = self.next_reserved_opt(MAX_FIELD_NUMBER)? {
+                r.reserved_nums.extend(field_nums);
+                r.reserved_names.extend(field_names);
+                return Ok(());
             }
 
-            if mode.is_extensions_allowed() {
-                if let Some(extension_ranges) = self.next_extensions_opt()? {
-                    r.extension_ranges.extend(extension_ranges);
-                    continue;
-                }
-            } else {
-                self.tokenizer.next_ident_if_eq_error("extensions")?;
+            if let Some(oneof) = self.next_oneof_opt()? {
+                let one_of = FieldOrOneOf::OneOf(oneof);
+                r.fields.push(WithLoc { t: one_of, loc });
+                return Ok(());
             }
 
-            if mode.is_option_allowed() {
-                if let Some(option) = self.next_option_opt()? {
-                    r.options.push(option);
-                    continue;
-                }
-            } else {
-                self.tokenizer.next_ident_if_eq_error("option")?;
+            if let Some(extensions) = self.next_extend_opt()? {
+                r.extensions.extend(extensions);
+                return Ok(());
             }
 
-            let field = FieldOrOneOf::Field(self.next_field(mode)?);
-            r.fields.push(WithLoc { t: field, loc });
+            if let Some(nested_message) = self.next_message_opt()? {
+                r.messages.push(nested_message);
+                return Ok(());
+            }
+
+            if let Some(nested_enum) = self.next_enum_opt()? {
+                r.enums.push(nested_enum);
+                return Ok(());
+            }
+        } else {
+            self.tokenizer.next_ident_if_eq_error("reserved")?;
+            self.tokenizer.next_ident_if_eq_error("oneof")?;
+            self.tokenizer.next_ident_if_eq_error("extend")?;
+            self.tokenizer.next_ident_if_eq_error("message")?;
+            self.tokenizer.next_ident_if_eq_error("enum")?;
+        }
+
+        if mode.is_extensions_allowed() {
+            if let Some(ranges) = self.next_extensions_opt()? {
+                extension_ranges.extend(ranges);
+                return Ok(());
+            }
+        } else {
+            self.tokenizer.next_ident_if_eq_error("extensions")?;
         }
 
-        self.tokenizer.next_symbol_expect_eq('}', "message body")?;
+        if mode.is_option_allowed() {
+            if let Some(option) = self.next_option_opt()? {
+                r.options.push(option);
+                return Ok(());
+            }
+        } else {
+            self.tokenizer.next_ident_if_eq_error("option")?;
+        }
+
+        let field = FieldOrOneOf::Field(self.next_field(mode)?);
+        r.fields.push(WithLoc { t: field, loc });
+        Ok(())
+    }
 
+    // Message body without braces, up to the end of input
+    fn next_message_body_fragment(&mut self) -> anyhow::Result<MessageBody> {
+        let mode = self.message_body_parse_mode();
+        let mut r = MessageBody::default();
+        let mut extension_ranges = Vec::new();
+        while !self.tokenizer.syntax_eof()? {
+            self.next_message_body_member(mode, &mut r, &mut extension_ranges)?;
+        }
+        Parser::resolve_extension_ranges(&mut r, extension_ranges)?;
+        self.check_message(&r.clone().into_message(String::new()))?;
         Ok(r)
     }
 
+    fn message_body_parse_mode(&self) -> MessageBodyParseMode {
+        match self.syntax {
+            Syntax::Proto2 => MessageBodyParseMode::MessageProto2,
+            Syntax::Proto3 => MessageBodyParseMode::MessageProto3,
+        }
+    }
+
+    fn check_message(&self, message: &Message) -> anyhow::Result<()> {
+        if self.syntax == Syntax::Proto3 {
+            Parser::check_json_names(message)?;
+        }
+        Ok(())
+    }
+
+    // Extension numbers above `MAX_FIELD_NUMBER` are only allowed in MessageSet,
+    // where `max` is `i32::MAX - 1`, elsewhere `max` is `MAX_FIELD_NUMBER`
+    fn resolve_extension_ranges(
+        body: &mut MessageBody,
+        ranges: Vec<ExtensionRangeDecl>,
+    ) -> anyhow::Result<()> {
+        let max = if model::is_message_set(&body.options) {
+            i32::MAX - 1
+        } else {
+            MAX_FIELD_NUMBER
+        };
+        let bound = |bound: WithLoc<u64>| -> anyhow::Result<i32> {
+            if bound.t > max as u64 {
+                return Err(ParserErrorAt {
+                    error: ParserError::RangeBoundOutOfRange(bound.t, max),
+                    loc: bound.loc,
+                }
+                .into());
+            }
+            Ok(bound.t as i32)
+        };
+        for range in ranges {
+            let start = bound(range.start)?;
+            let end = match range.end {
+                Some(end) => bound(end)?,
+                None => max,
+            };
+            body.extension_ranges.push(start..=end);
+        }
+        Ok(())
+    }
+
+    // Fields of proto3 message must have distinct JSON names,
+    // a conflict is located at the latter field
+    fn check_json_names(message: &Message) -> anyhow::Result<()> {
+        let mut json_names: HashMap<String, &str> = HashMap::new();
+        for f in message.regular_fields_including_in_oneofs() {
+            match json_names.entry(f.t.json_name()?) {
+                Entry::Occupied(e) => {
+                    let error = ParserError::JsonNameConflict {
+                        a: e.get().to_string(),
+                        b: f.t.name.clone(),
+                    };
+                    return Err(ParserErrorAt { error, loc: f.loc }.into());
+                }
+                Entry::Vacant(e) => {
+                    e.insert(&f.t.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
     // message = "message" messageName messageBody
     fn next_message_opt(&mut self) -> anyhow::Result<Option<WithLoc<Message>>> {
         let loc = self.tokenizer.lookahead_loc();
@@ -997,33 +1572,10 @@ impl<'a> Parser<'a> {
         if self.tokenizer.next_ident_if_eq("message")? {
             let name = self.tokenizer.next_ident()?.to_owned();
 
-            let mode = match self.syntax {
-                Syntax::Proto2 => MessageBodyParseMode::MessageProto2,
-                Syntax::Proto3 => MessageBodyParseMode::MessageProto3,
-            };
-
-            let MessageBody {
-                fields,
-                reserved_nums,
-                reserved_names,
-                messages,
-                enums,
-                options,
-                extensions,
-                extension_ranges,
-            } = self.next_message_body(mode)?;
+            let mode = self.message_body_parse_mode();
+            let message = self.next_message_body(mode)?.into_message(name);
+            self.check_message(&message)?;
 
-            let message = Message {
-                name,
-                fields,
-                reserved_nums,
-                reserved_names,
-                messages,
-                enums,
-                options,
-                extensions,
-                extension_ranges,
-            };
             Ok(Some(WithLoc { t: message, loc }))
         } else {
             Ok(None)
@@ -1077,9 +1629,11 @@ impl<'a> Parser<'a> {
 
     // Service definition
 
-    fn next_options_or_colon(&mut self) -> anyhow::Result<Vec<ProtobufOption>> {
+    /// Options and whether they were written in a block
+    fn next_options_or_colon(&mut self) -> anyhow::Result<(Vec<ProtobufOption>, bool)> {
         let mut options = Vec::new();
-        if self.tokenizer.next_symbol_if_eq('{')? {
+        let has_options_block = self.tokenizer.next_symbol_if_eq('{')?;
+        if has_options_block {
             while self.tokenizer.lookahead_if_symbol()? != Some('}') {
                 if let Some(option) = self.next_option_opt()? {
                     options.push(option);
@@ -1097,7 +1651,7 @@ impl<'a> Parser<'a> {
             self.tokenizer.next_symbol_expect_eq(';', "option")?;
         }
 
-        Ok(options)
+        Ok((options, has_options_block))
     }
 
     // stream = "stream" streamName "(" messageType "," messageType ")"
@@ -1107,18 +1661,23 @@ impl<'a> Parser<'a> {
         if self.tokenizer.next_ident_if_eq("stream")? {
             let name = self.tokenizer.next_ident()?;
             self.tokenizer.next_symbol_expect_eq('(', "stream")?;
-            let input_type = self.next_message_or_enum_type()?;
+            let input_type_loc = self.tokenizer.lookahead_loc();
+            let input_type = self.next_rpc_type()?;
             self.tokenizer.next_symbol_expect_eq(',', "stream")?;
-            let output_type = self.next_message_or_enum_type()?;
+            let output_type_loc = self.tokenizer.lookahead_loc();
+            let output_type = self.next_rpc_type()?;
             self.tokenizer.next_symbol_expect_eq(')', "stream")?;
-            let options = self.next_options_or_colon()?;
+            let (options, has_options_block) = self.next_options_or_colon()?;
             Ok(Some(Method {
                 name,
                 input_type,
+                input_type_loc,
                 output_type,
+                output_type_loc,
                 client_streaming: true,
                 server_streaming: true,
                 options,
+                has_options_block,
             }))
         } else {
             Ok(None)
@@ -1133,21 +1692,26 @@ impl<'a> Parser<'a> {
             let name = self.tokenizer.next_ident()?;
             self.tokenizer.next_symbol_expect_eq('(', "rpc")?;
             let client_streaming = self.tokenizer.next_ident_if_eq("stream")?;
-            let input_type = self.next_message_or_enum_type()?;
+            let input_type_loc = self.tokenizer.lookahead_loc();
+            let input_type = self.next_rpc_type()?;
             self.tokenizer.next_symbol_expect_eq(')', "rpc")?;
             self.tokenizer.next_ident_expect_eq("returns")?;
             self.tokenizer.next_symbol_expect_eq('(', "rpc")?;
             let server_streaming = self.tokenizer.next_ident_if_eq("stream")?;
-            let output_type = self.next_message_or_enum_type()?;
+            let output_type_loc = self.tokenizer.lookahead_loc();
+            let output_type = self.next_rpc_type()?;
             self.tokenizer.next_symbol_expect_eq(')', "rpc")?;
-            let options = self.next_options_or_colon()?;
+            let (options, has_options_block) = self.next_options_or_colon()?;
             Ok(Some(Method {
                 name,
                 input_type,
+                input_type_loc,
                 output_type,
+                output_type_loc,
                 client_streaming,
                 server_streaming,
                 options,
+                has_options_block,
             }))
         } else {
             Ok(None)
@@ -1206,6 +1770,84 @@ impl<'a> Parser<'a> {
     }
 
     pub fn next_proto(&mut self) -> anyhow::Result<FileDescriptor> {
+        self.next_file(false)
+    }
+
+    /// Parses a file passing each top-level statement to `f`
+    /// instead of collecting them into a `FileDescriptor`.
+    ///
+    /// Parsing stops at the first error returned by `f`.
+    /// Local names are not resolved.
+    pub fn for_each_top_level(
+        &mut self,
+        mut f: impl FnMut(TopLevel) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let syntax = self.next_syntax()?;
+        self.syntax = syntax.unwrap_or(Syntax::Proto2);
+        if let Some(syntax) = syntax {
+            f(TopLevel::Syntax(syntax))?;
+        }
+
+        while let Some((_, top_level)) = self.next_top_level_opt()? {
+            f(top_level)?;
+        }
+
+        if !self.tokenizer.syntax_eof()? {
+            return Err(ParserError::MisplacedSyntax.into());
+        }
+        Ok(())
+    }
+
+    // topLevelDef = import | package | option | message | enum | extend | service
+    //
+    // Returns `None` at the end of input or when `syntax` is next,
+    // skipping empty statements.
+    fn next_top_level_opt(&mut self) -> anyhow::Result<Option<(Loc, TopLevel)>> {
+        // emptyStatement, which also accepts `;` after a block (`message M {};`)
+        while self.tokenizer.next_symbol_if_eq(';')? {}
+
+        if self.tokenizer.syntax_eof()? {
+            return Ok(None);
+        }
+
+        let loc = self.tokenizer.lookahead_loc();
+
+        let top_level = if let Some(import) = self.next_import_opt()? {
+            TopLevel::Import(import)
+        } else if let Some(package) = self.next_package_opt()? {
+            TopLevel::Package(package)
+        } else if let Some(option) = self.next_option_opt()? {
+            TopLevel::Option(option)
+        } else if let Some(message) = self.next_message_opt()? {
+            TopLevel::Message(message)
+        } else if let Some(enumeration) = self.next_enum_opt()? {
+            TopLevel::Enum(enumeration)
+        } else if let Some(extensions) = self.next_extend_opt()? {
+            TopLevel::Extensions(extensions)
+        } else if let Some(service) = self.next_service_opt()? {
+            TopLevel::Service(service)
+        } else if self.tokenizer.lookahead_is_ident("syntax")? {
+            // Leave `syntax` in lookahead so the error points to it
+            return Ok(None);
+        } else {
+            return Err(ParserError::IncorrectInput.into());
+        };
+
+        Ok(Some((loc, top_level)))
+    }
+
+    // Files concatenated into one input, each starting with a `syntax` statement,
+    // except for the first one
+    fn next_files(&mut self) -> anyhow::Result<Vec<FileDescriptor>> {
+        let mut files = Vec::new();
+        while !self.tokenizer.syntax_eof()? {
+            files.push(self.next_file(true)?);
+        }
+        Ok(files)
+    }
+
+    // If `concatenated`, a `syntax` statement ends the file instead of being an error
+    fn next_file(&mut self, concatenated: bool) -> anyhow::Result<FileDescriptor> {
         let syntax = self.next_syntax()?.unwrap_or(Syntax::Proto2);
         self.syntax = syntax;
 
@@ -1217,58 +1859,1313 @@ impl<'a> Parser<'a> {
         let mut options = Vec::new();
         let mut services = Vec::new();
 
-        while !self.tokenizer.syntax_eof()? {
-            if let Some(import) = self.next_import_opt()? {
-                imports.push(import);
-                continue;
+        while let Some((loc, top_level)) = self.next_top_level_opt()? {
+            match top_level {
+                TopLevel::Import(import) => {
+                    // Imports are accepted anywhere, but should precede declarations
+                    if !messages.is_empty()
+                        || !enums.is_empty()
+                        || !extensions.is_empty()
+                        || !services.is_empty()
+                    {
+                        self.warn(ParserWarning::LateImport(import.path.clone()), loc);
+                    }
+                    imports.push(import);
+                }
+                TopLevel::Package(next_package) => package = next_package,
+                TopLevel::Option(option) => options.push(option),
+                TopLevel::Message(message) => messages.push(message),
+                TopLevel::Enum(enumeration) => enums.push(enumeration),
+                TopLevel::Extensions(more_extensions) => extensions.extend(more_extensions),
+                TopLevel::Service(service) => services.push(service),
+                TopLevel::Syntax(..) => unreachable!(),
             }
+        }
+
+        if !concatenated && !self.tokenizer.syntax_eof()? {
+            return Err(ParserError::MisplacedSyntax.into());
+        }
+
+        let mut file = FileDescriptor {
+            imports,
+            package,
+            syntax,
+            messages,
+            enums,
+            extensions,
+            services,
+            options,
+        };
+
+        convert::check_method_types(&file)?;
+        convert::check_extension_numbers(&file)?;
+        if self.resolve_local_names || self.known_types.is_some() {
+            convert::resolve_local_names(&mut file)?;
+        }
+        if let Some(known_types) = &self.known_types {
+            convert::resolve_known_types(&mut file, known_types)?;
+        }
+
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str) -> FileDescriptor {
+        FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn parse_err(input: &str) -> ParserErrorWithLocation {
+        match FileDescriptor::parse(input) {
+            Ok(file) => panic!("expecting an error, parsed {:?}", file),
+            Err(e) => e,
+        }
+    }
+
+    fn next_proto_err(parser: &mut Parser) -> ParserErrorWithLocation {
+        match parser.next_proto() {
+            Ok(file) => panic!("expecting an error, parsed {:?}", file),
+            Err(e) => ParserErrorWithLocation::new(e, &parser.tokenizer),
+        }
+    }
+
+    fn parser_error(e: &ParserErrorWithLocation) -> &ParserError {
+        e.error
+            .downcast_ref()
+            .unwrap_or_else(|| panic!("not a ParserError: {}", e))
+    }
 
-            if let Some(next_package) = self.next_package_opt()? {
-                package = next_package;
-                continue;
+    fn regular(name: &str) -> ProtobufConstantMessageFieldName {
+        ProtobufConstantMessageFieldName::Regular(name.to_owned())
+    }
+
+    #[test]
+    fn test_comments_in_aggregate_option() {
+        let file = parse(
+            r#"
+            option (ext) = {
+                a: 1 // trailing
+                /* block */
+                // line
+                b: "x"
+            };
+            "#,
+        );
+        let m = match &file.options[0].value {
+            ProtobufConstant::Message(m) => m,
+            v => panic!("{:?}", v),
+        };
+        assert_eq!(
+            vec![&regular("a"), &regular("b")],
+            m.fields.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(&ProtobufConstant::U64(1)), m.fields.get(&regular("a")));
+    }
+
+    #[test]
+    fn test_map_value_with_leading_dot() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                map<string, .foo.Bar> m = 1;
+                map<int32, foo.Bar> n = 2;
             }
+            "#,
+        );
+        let fields = file.messages[0].regular_fields_for_test();
+        assert_eq!(
+            FieldType::Map(Box::new((
+                FieldType::String,
+                FieldType::MessageOrEnum(ProtobufPath::new(".foo.Bar".to_owned()))
+            ))),
+            fields[0].typ
+        );
+        match fields[0].typ.element_type() {
+            FieldType::MessageOrEnum(ProtobufPath::Abs(p)) => assert_eq!(".foo.Bar", p.path),
+            t => panic!("{:?}", t),
+        }
+        match fields[1].typ.element_type() {
+            FieldType::MessageOrEnum(ProtobufPath::Rel(p)) => assert_eq!("foo.Bar", p.to_string()),
+            t => panic!("{:?}", t),
+        }
+    }
 
-            if let Some(option) = self.next_option_opt()? {
-                options.push(option);
-                continue;
+    #[test]
+    fn test_text_message() {
+        let m = Parser::parse_text_message(
+            r#"
+            # comment
+            name: "x"
+            id: 1, flag: true;
+            nested { a: 1 }
+            nested < a: 2 >
+            values: [1, 2]
+            values: 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["name", "id", "flag", "nested", "values"],
+            m.fields.keys().map(|n| n.to_string()).collect::<Vec<_>>()
+        );
+        match &m.fields[&regular("nested")] {
+            ProtobufConstant::Repeated(v) => assert_eq!(2, v.len()),
+            v => panic!("{:?}", v),
+        }
+        assert_eq!(
+            ProtobufConstant::Repeated(vec![
+                ProtobufConstant::U64(1),
+                ProtobufConstant::U64(2),
+                ProtobufConstant::U64(3),
+            ]),
+            m.fields[&regular("values")]
+        );
+    }
+
+    #[test]
+    fn test_text_message_round_trip() {
+        let input = "a: 1 b { c: \"x\" d: [E, F] } b { c: \"y\" }";
+        let m = Parser::parse_text_message(input).unwrap();
+        let formatted = m.format();
+        let inner = &formatted[1..formatted.len() - 1];
+        assert_eq!(m, Parser::parse_text_message(inner).unwrap());
+        assert_eq!(
+            ProtobufConstant::Message(m),
+            Parser::parse_constant(&formatted).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_text_message_empty() {
+        assert_eq!(
+            ProtobufConstantMessage::default(),
+            Parser::parse_text_message(" # nothing\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_text_message_error_location() {
+        let e = Parser::parse_text_message("a: 1\nb: }").unwrap_err();
+        assert_eq!((2, 4), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_enum_value_uses_reserved_number() {
+        let e = parse_err(
+            r#"enum E {
+                reserved 2, 10 to 20;
+                A = 1;
+                B = 15;
+            }"#,
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::EnumValueUsesReserved(name) if name == "B"
+        ));
+        assert_eq!((4, 17), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_enum_value_uses_reserved_name() {
+        let e = parse_err(
+            r#"enum E {
+                A = 0;
+                B = 1;
+                reserved "B";
+            }"#,
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::EnumValueUsesReserved(name) if name == "B"
+        ));
+        assert_eq!((3, 17), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_enum_reserved_not_used() {
+        let file = parse(
+            r#"enum E {
+                reserved 2 to 5, 100 to max;
+                reserved "C";
+                A = 1;
+                B = 6;
+                D = -1;
+            }"#,
+        );
+        assert_eq!(3, file.enums[0].values.len());
+    }
+
+    fn field_type<'f>(message: &'f Message, name: &str) -> &'f FieldType {
+        &message.field_by_name(name).unwrap().typ
+    }
+
+    fn message_or_enum(path: &str) -> FieldType {
+        FieldType::MessageOrEnum(ProtobufPath::new(path.to_owned()))
+    }
+
+    #[test]
+    fn test_resolve_local_names() {
+        let file = Parser::new(
+            r#"
+            syntax = "proto3";
+            package p;
+            message Outer {
+                message Inner {
+                    Outer outer = 1;
+                    Inner inner = 2;
+                    enum E { Z = 0; }
+                }
+                Inner inner = 1;
+                map<string, Inner.E> m = 2;
+                ext.Ext external = 3;
+            }
+            message Inner {}
+            extend Outer { Inner inner = 100; }
+            service S { rpc M(Outer.Inner) returns (.p.Outer); }
+            "#,
+        )
+        .resolve_local_names(true)
+        .next_proto()
+        .unwrap();
+        let outer = &file.messages[0];
+        let inner = &outer.messages[0];
+        assert_eq!(&message_or_enum(".p.Outer"), field_type(inner, "outer"));
+        assert_eq!(
+            &message_or_enum(".p.Outer.Inner"),
+            field_type(inner, "inner")
+        );
+        assert_eq!(
+            &message_or_enum(".p.Outer.Inner"),
+            field_type(outer, "inner")
+        );
+        assert_eq!(
+            &message_or_enum(".p.Outer.Inner.E"),
+            field_type(outer, "m").element_type()
+        );
+        assert_eq!(&message_or_enum("ext.Ext"), field_type(outer, "external"));
+        let extension = &file.extensions[0];
+        assert_eq!(".p.Outer", extension.extendee.to_string());
+        assert_eq!(message_or_enum(".p.Inner"), extension.field.typ);
+        let method = &file.services[0].methods[0];
+        assert_eq!(".p.Outer.Inner", method.input_type.to_string());
+        assert_eq!(".p.Outer", method.output_type.to_string());
+    }
+
+    #[test]
+    fn test_resolve_local_names_disabled() {
+        let file = parse("message A { optional B b = 1; message B {} }");
+        assert_eq!(&message_or_enum("B"), field_type(&file.messages[0], "b"));
+    }
+
+    #[test]
+    fn test_resolve_local_names_unresolved() {
+        let mut parser = Parser::new("package p;\nmessage A {\n  optional A.Missing b = 1;\n}");
+        let e = next_proto_err(parser.resolve_local_names(true));
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::UnresolvedLocalName(name, scope)
+                if name.to_string() == "A.Missing" && scope.to_string() == ".p.A"
+        ));
+        assert_eq!((3, 3), (e.line, e.col));
+
+        // Extendee is located at the extension, method types at themselves
+        let mut parser = Parser::new("message A {}\nextend A.B {\n  optional int32 x = 1;\n}");
+        let e = next_proto_err(parser.resolve_local_names(true));
+        assert_eq!((3, 3), (e.line, e.col));
+
+        let mut parser = Parser::new("message A {}\nservice S {\n  rpc M(A) returns (A.B);\n}");
+        let e = next_proto_err(parser.resolve_local_names(true));
+        assert_eq!((3, 21), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_syntax_after_message() {
+        let e = parse_err("message M {}\nsyntax = \"proto3\";");
+        assert!(matches!(parser_error(&e), ParserError::MisplacedSyntax));
+        assert_eq!((2, 1), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_duplicate_syntax() {
+        let e = parse_err("syntax = \"proto3\";\n  syntax = \"proto3\";");
+        assert!(matches!(parser_error(&e), ParserError::MisplacedSyntax));
+        assert_eq!((2, 3), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_syntax_first() {
+        assert_eq!(Syntax::Proto3, parse("syntax = \"proto3\";").syntax);
+        assert_eq!(
+            Syntax::Proto2,
+            parse("syntax = 'proto2'; message M {}").syntax
+        );
+    }
+
+    #[test]
+    fn test_field_number_overflows_u64() {
+        let e = parse_err("message M {\n  optional int32 a = 99999999999999999999999;\n}");
+        assert!(matches!(parser_error(&e), ParserError::IntegerOverflow));
+        // Located at the preceding token
+        assert_eq!((2, 20), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_enum_value_overflows_u64() {
+        let e = parse_err("enum E {\n  A = -99999999999999999999999;\n}");
+        assert!(matches!(parser_error(&e), ParserError::IntegerOverflow));
+        assert_eq!((2, 7), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_enum_value_overflows_i32() {
+        let e = parse_err("enum E {\n  A = 2147483648;\n}");
+        assert!(matches!(parser_error(&e), ParserError::IntegerOverflow));
+        assert_eq!((2, 7), (e.line, e.col));
+        let file = parse("enum E { A = -2147483648; B = 2147483647; }");
+        assert_eq!(i32::MIN, file.enums[0].values[0].number);
+        assert_eq!(i32::MAX, file.enums[0].values[1].number);
+    }
+
+    #[test]
+    fn test_bool_option_values() {
+        let file = parse(
+            r#"
+            message M {
+                optional int32 a = 1 [deprecated = true, packed = false];
+                optional int32 b = 2 [deprecated = True];
+            }
+            "#,
+        );
+        let fields = file.messages[0].regular_fields_for_test();
+        assert_eq!(ProtobufConstant::Bool(true), fields[0].options[0].value);
+        assert_eq!(Some(true), fields[0].options[0].value.as_bool());
+        assert_eq!(Some(false), fields[0].options[1].value.as_bool());
+        assert_eq!(
+            ProtobufConstant::Ident(ProtobufPath::new("True")),
+            fields[1].options[0].value
+        );
+        assert_eq!(None, fields[1].options[0].value.as_bool());
+    }
+
+    #[test]
+    fn test_late_import_warning() {
+        let mut parser = Parser::new(
+            r#"import "a.proto";
+            message M {}
+            import "b.proto";
+            import "c.proto";"#,
+        );
+        let file = parser.next_proto().unwrap();
+        assert_eq!(3, file.imports.len());
+        let late_import = |path: &str, line| ParserWarningWithLocation {
+            warning: ParserWarning::LateImport(ProtoPathBuf::new(path.to_owned()).unwrap()),
+            line,
+            col: 13,
+        };
+        assert_eq!(
+            vec![late_import("b.proto", 3), late_import("c.proto", 4)],
+            parser.warnings()
+        );
+    }
+
+    #[test]
+    fn test_no_late_import_warning() {
+        let mut parser = Parser::new("package p; import \"a.proto\"; option x = 1; message M {}");
+        parser.next_proto().unwrap();
+        assert!(parser.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_semicolon_after_blocks() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                message N {};
+                enum F { Z = 0; };
+                int32 a = 1;
+            };
+            enum E { Z = 0; };
+            service S { rpc A(M) returns (M); };
+            extend M { int32 b = 100; };
+            ;;
+            "#,
+        );
+        assert_eq!(1, file.messages.len());
+        assert_eq!(1, file.messages[0].messages.len());
+        assert_eq!(1, file.messages[0].enums.len());
+        assert_eq!(1, file.enums.len());
+        assert_eq!(1, file.services.len());
+        assert_eq!(1, file.extensions.len());
+    }
+
+    #[test]
+    fn test_semicolon_after_empty_blocks() {
+        let file = parse("message M {};\nenum E {};\nservice S {};");
+        assert_eq!("M", file.messages[0].name);
+        assert_eq!("E", file.enums[0].name);
+        assert_eq!("S", file.services[0].name);
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let files = Parser::parse_all(
+            r#"
+            package a;
+            message A {}
+            syntax = "proto3";
+            package b;
+            message B {}
+            syntax = "proto2";
+            enum C { Z = 0; }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(3, files.len());
+        let syntaxes: Vec<Syntax> = files.iter().map(|f| f.syntax).collect();
+        assert_eq!(
+            vec![Syntax::Proto2, Syntax::Proto3, Syntax::Proto2],
+            syntaxes
+        );
+        let packages: Vec<String> = files.iter().map(|f| f.package.to_string()).collect();
+        assert_eq!(vec![".a", ".b", ""], packages);
+        assert_eq!("A", files[0].messages[0].name);
+        assert_eq!("B", files[1].messages[0].name);
+        assert_eq!("C", files[2].enums[0].name);
+    }
+
+    #[test]
+    fn test_parse_all_empty() {
+        assert!(Parser::parse_all("").unwrap().is_empty());
+        assert!(Parser::parse_all(" // comment\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_all_error() {
+        let e = Parser::parse_all("syntax = \"proto3\";\nsyntax = \"proto3\";\nfoo").unwrap_err();
+        assert_eq!((3, 1), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_value_span_of_concatenated_str_lit() {
+        let input = "message M {\n  optional string s = 1 [default = \"ab\" \"cd\"];\n}\n";
+        let file = parse(input);
+        let option = &file.messages[0].t.field_by_name("s").unwrap().options[0];
+        let span = option.value_span.unwrap();
+        assert_eq!("\"ab\" \"cd\"", &input[span.start..span.end]);
+    }
+
+    #[test]
+    fn test_aggregate_option_with_eq() {
+        let file = parse("option (x) = { a: 1 };");
+        match &file.options[0].value {
+            ProtobufConstant::Message(m) => {
+                assert_eq!(Some(&ProtobufConstant::U64(1)), m.fields.get(&regular("a")))
             }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_option_without_eq() {
+        let e = parse_err("option (x) { a: 1 };");
+        assert_eq!((1, 12), (e.line, e.col));
+    }
 
-            if let Some(message) = self.next_message_opt()? {
-                messages.push(message);
-                continue;
+    #[test]
+    fn test_method_options_block() {
+        let file = parse(
+            r#"
+            service S {
+                rpc A(M) returns (M);
+                rpc B(M) returns (M) {}
+                rpc C(M) returns (M) { option deprecated = true; }
+                stream D(M, M) {}
+                stream E(M, M);
             }
+            "#,
+        );
+        let blocks: Vec<(&str, bool)> = file.services[0]
+            .t
+            .methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.has_options_block))
+            .collect();
+        assert_eq!(
+            vec![
+                ("A", false),
+                ("B", true),
+                ("C", true),
+                ("D", true),
+                ("E", false)
+            ],
+            blocks
+        );
+    }
 
-            if let Some(enumeration) = self.next_enum_opt()? {
-                enums.push(enumeration);
-                continue;
+    #[test]
+    fn test_range_bound_out_of_range() {
+        let e = parse_err("message M {\n  extensions 1 to 99999999999;\n}");
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RangeBoundOutOfRange(99999999999, MAX_FIELD_NUMBER)
+        ));
+        assert_eq!((2, 19), (e.line, e.col));
+
+        let e = parse_err("message M { reserved 536870912; }");
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RangeBoundOutOfRange(536870912, MAX_FIELD_NUMBER)
+        ));
+
+        let e = parse_err("enum E { Z = 0; reserved 1 to 2147483648; }");
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RangeBoundOutOfRange(2147483648, i32::MAX)
+        ));
+    }
+
+    #[test]
+    fn test_range_bound_max() {
+        let file = parse(
+            r#"
+            message M {
+                extensions 1 to 536870911;
+                reserved 10 to max;
             }
+            enum E { Z = 0; reserved 1 to 2147483647; }
+            "#,
+        );
+        let m = &file.messages[0].t;
+        assert_eq!(vec![1..=MAX_FIELD_NUMBER], m.extension_ranges);
+        assert_eq!(vec![10..=MAX_FIELD_NUMBER], m.reserved_nums);
+        assert_eq!(vec![1..=i32::MAX], file.enums[0].t.reserved_nums);
+    }
+
+    #[test]
+    fn test_for_each_top_level() {
+        let mut parser = Parser::new(
+            r#"
+            syntax = "proto3";
+            package p;
+            message A {}
+            enum E { Z = 0; }
+            message B { message C {} }
+            service S {}
+            "#,
+        );
+        let mut messages = Vec::new();
+        let mut others = 0;
+        parser
+            .for_each_top_level(|top_level| {
+                match top_level {
+                    TopLevel::Message(m) => messages.push(m.t.name),
+                    _ => others += 1,
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec!["A", "B"], messages);
+        assert_eq!(4, others);
+    }
+
+    #[test]
+    fn test_for_each_top_level_stops_on_error() {
+        let mut parser = Parser::new("message A {}\nmessage B {}\nmessage C {}\n");
+        let mut count = 0;
+        let e = parser
+            .for_each_top_level(|_| {
+                count += 1;
+                if count == 2 {
+                    return Err(anyhow::anyhow!("stop"));
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!("stop", e.to_string());
+        assert_eq!(2, count);
+    }
 
-            if let Some(more_extensions) = self.next_extend_opt()? {
-                extensions.extend(more_extensions);
-                continue;
+    #[test]
+    fn test_rpc_scalar_type() {
+        let e = parse_err("message Foo {}\nservice S {\n  rpc M(int32) returns (Foo);\n}");
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RpcTypeMustBeMessage(name) if name == "int32"
+        ));
+        assert_eq!((3, 9), (e.line, e.col));
+
+        let e = parse_err("message Foo {}\nservice S {\n  stream M(Foo, string);\n}");
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RpcTypeMustBeMessage(name) if name == "string"
+        ));
+        assert_eq!((3, 17), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_rpc_enum_type() {
+        let input =
+            "message Foo { enum E { Z = 0; } }\nservice S {\n  rpc M(Foo) returns (Foo.E);\n}";
+        for resolve in [false, true] {
+            let mut parser = Parser::new(input);
+            parser.resolve_local_names(resolve);
+            let e = parser.next_proto().unwrap_err();
+            let e = ParserErrorWithLocation::new(e, &parser.tokenizer);
+            assert!(matches!(
+                parser_error(&e),
+                ParserError::RpcTypeMustBeMessage(name) if name == "Foo.E"
+            ));
+            assert_eq!((3, 23), (e.line, e.col));
+        }
+    }
+
+    #[test]
+    fn test_rpc_external_type() {
+        let file = parse("import \"a.proto\";\nservice S {\n  rpc M(a.E) returns (a.E);\n}");
+        assert_eq!("a.E", file.services[0].t.methods[0].input_type.to_string());
+    }
+
+    #[test]
+    fn test_option_locations() {
+        let file = parse(
+            r#"option java_package = "p";
+message M {
+  option deprecated = true;
+  optional int32 a = 1 [default = 1, (x).y = 2];
+}
+enum E {
+  Z = 0 [deprecated = true];
+}
+service S {
+  rpc R(M) returns (M) { option deprecated = true; }
+}
+"#,
+        );
+        let loc = |o: &ProtobufOption| (o.loc.line, o.loc.col);
+        assert_eq!((1, 1), loc(&file.options[0]));
+        let m = &file.messages[0].t;
+        assert_eq!((3, 3), loc(&m.options[0]));
+        let a = m.field_by_name("a").unwrap();
+        assert_eq!(
+            vec![(4, 25), (4, 38)],
+            a.options.iter().map(loc).collect::<Vec<_>>()
+        );
+        assert_eq!((7, 10), loc(&file.enums[0].t.values[0].options[0]));
+        assert_eq!((10, 26), loc(&file.services[0].t.methods[0].options[0]));
+    }
+
+    #[test]
+    fn test_json_name_conflict() {
+        let e = parse_err(
+            "syntax = \"proto3\";\nmessage M {\n  int32 fooBar = 1;\n  int32 foo_bar = 2;\n}",
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::JsonNameConflict { a, b } if a == "fooBar" && b == "foo_bar"
+        ));
+        assert_eq!((4, 3), (e.line, e.col));
+
+        let e = parse_err(
+            r#"syntax = "proto3";
+            message M {
+                int32 a = 1 [json_name = "x"];
+                oneof o { int32 b = 2 [json_name = "x"]; }
+            }"#,
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::JsonNameConflict { a, b } if a == "a" && b == "b"
+        ));
+        assert_eq!((4, 27), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_no_json_name_conflict() {
+        // Only checked in proto3
+        parse("message M { optional int32 fooBar = 1; optional int32 foo_bar = 2; }");
+        // Nested messages are checked separately
+        parse(
+            "syntax = \"proto3\"; message M { int32 fooBar = 1; message N { int32 foo_bar = 1; } }",
+        );
+        parse(
+            r#"syntax = "proto3";
+            message M { int32 foo_bar = 1 [json_name = "other"]; int32 fooBar = 2; }"#,
+        );
+    }
+
+    #[test]
+    fn test_parse_constant() {
+        assert_eq!(
+            ProtobufConstant::U64(10),
+            Parser::parse_constant("10").unwrap()
+        );
+        assert_eq!(
+            ProtobufConstant::I64(-1),
+            Parser::parse_constant(" -1 ").unwrap()
+        );
+        assert!(matches!(
+            Parser::parse_constant("{ a: 1 }").unwrap(),
+            ProtobufConstant::Message(..)
+        ));
+    }
+
+    #[test]
+    fn test_parse_constant_trailing_input() {
+        let e = Parser::parse_constant("10 x").unwrap_err();
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::TrailingInput { found, context } if found == "x" && context == "constant"
+        ));
+        assert_eq!((1, 4), (e.line, e.col));
+
+        let e = Parser::parse_constant("{ a: 1 } }").unwrap_err();
+        assert_eq!((1, 10), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_parse_field_type() {
+        assert_eq!(
+            FieldType::Sint64,
+            Parser::parse_field_type("sint64").unwrap()
+        );
+        assert_eq!(
+            message_or_enum(".a.B"),
+            Parser::parse_field_type(".a.B").unwrap()
+        );
+        assert_eq!(
+            FieldType::Map(Box::new((FieldType::String, FieldType::Int32))),
+            Parser::parse_field_type("map<string, int32>").unwrap()
+        );
+
+        let e = Parser::parse_field_type("int32 x").unwrap_err();
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::TrailingInput { found, context } if found == "x" && context == "field type"
+        ));
+        assert_eq!((1, 7), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_message_set_extension_ranges() {
+        let file = parse(
+            r#"
+            message S {
+                option message_set_wire_format = true;
+                extensions 4 to max;
+                extensions 10 to 536870911, 600000000 to 2147483646;
+            }
+            message M {
+                extensions 4 to max;
             }
+            "#,
+        );
+        assert_eq!(
+            vec![
+                4..=i32::MAX - 1,
+                10..=MAX_FIELD_NUMBER,
+                600000000..=i32::MAX - 1
+            ],
+            file.messages[0].t.extension_ranges
+        );
+        assert_eq!(
+            vec![4..=MAX_FIELD_NUMBER],
+            file.messages[1].t.extension_ranges
+        );
+
+        // Option after the ranges
+        let file =
+            parse("message S { extensions 4 to max; option message_set_wire_format = true; }");
+        assert_eq!(vec![4..=i32::MAX - 1], file.messages[0].t.extension_ranges);
+    }
+
+    #[test]
+    fn test_extension_ranges_out_of_range() {
+        for (input, col) in [
+            ("message M { extensions 1 to 536870912; }", 29),
+            ("message M { extensions 536870912 to max; }", 24),
+            (
+                "message M { option message_set_wire_format = false; extensions 1 to 536870912; }",
+                69,
+            ),
+            (
+                "message M {\n  extensions 1 to 536870912;\n  option deprecated = true;\n}",
+                19,
+            ),
+        ] {
+            let e = parse_err(input);
+            assert!(
+                matches!(
+                    parser_error(&e),
+                    ParserError::RangeBoundOutOfRange(536870912, MAX_FIELD_NUMBER)
+                ),
+                "{}",
+                input
+            );
+            assert_eq!(col, e.col, "{}", input);
+        }
+
+        let e = parse_err(
+            "message S { option message_set_wire_format = true; extensions 1 to 2147483647; }",
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RangeBoundOutOfRange(2147483647, max) if *max == i32::MAX - 1
+        ));
+        assert_eq!((1, 68), (e.line, e.col));
+    }
 
-            if let Some(service) = self.next_service_opt()? {
-                services.push(service);
-                continue;
+    #[test]
+    fn test_extension_number_out_of_range() {
+        let e = parse_err(
+            "message M { extensions 1 to max; }\n\
+             extend M { optional int32 x = 1000000000; }",
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::FieldNumberOutOfRange(1000000000, MAX_FIELD_NUMBER)
+        ));
+        assert_eq!((2, 12), (e.line, e.col));
+
+        // Extendee resolved from the scope of a nested extension
+        let e = parse_err(
+            r#"
+            package p;
+            message M {
+                extensions 1 to max;
+                message N {
+                    extend M { optional int32 x = 536870912; }
+                }
+            }
+            "#,
+        );
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::FieldNumberOutOfRange(536870912, MAX_FIELD_NUMBER)
+        ));
+
+        let file = parse(
+            r#"
+            message S {
+                option message_set_wire_format = true;
+                extensions 4 to max;
+            }
+            extend S { optional int32 x = 1000000000; }
+            extend M { optional int32 y = 536870911; }
+            extend Other { optional int32 z = 1000000000; }
+            "#,
+        );
+        let numbers: Vec<i32> = file.extensions.iter().map(|e| e.t.field.t.number).collect();
+        assert_eq!(vec![1000000000, 536870911, 1000000000], numbers);
+    }
+
+    #[test]
+    fn test_lenient_missing_option_semicolon() {
+        let input = r#"option java_package = "p"
+message M {
+  option deprecated = true
+  optional int32 a = 1;
+  option (x) = 2
+}
+enum E { option allow_alias = true Z = 0; }
+service S { option deprecated = true }"#;
+        let mut parser = Parser::new(input);
+        parser.lenient(true);
+        let file = parser.next_proto().unwrap();
+        assert_eq!(1, file.options.len());
+        assert_eq!(2, file.messages[0].t.options.len());
+        assert_eq!(1, file.messages[0].t.fields.len());
+        assert_eq!(1, file.enums[0].t.options.len());
+        assert_eq!(1, file.services[0].t.options.len());
+        let missing = |name: &str, line, col| ParserWarningWithLocation {
+            warning: ParserWarning::MissingOptionSemicolon(name.to_owned()),
+            line,
+            col,
+        };
+        assert_eq!(
+            vec![
+                missing("java_package", 2, 1),
+                missing("deprecated", 4, 3),
+                missing("(x)", 6, 1),
+                missing("allow_alias", 7, 36),
+                missing("deprecated", 8, 38),
+            ],
+            parser.warnings()
+        );
+
+        let e = parse_err(input);
+        assert_eq!((2, 1), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_lenient_option_semicolon_required() {
+        for input in [
+            "option x = 1",
+            "option x = 1 = 2;",
+            "message M { option x = 1 1; }",
+            "message M { option x = 1 \"s\"; }",
+        ] {
+            let mut parser = Parser::new(input);
+            parser.lenient(true);
+            assert!(parser.next_proto().is_err(), "{}", input);
+        }
+
+        let mut parser = Parser::new("option x = 1; message M { option y = 2; }");
+        parser.lenient(true);
+        parser.next_proto().unwrap();
+        assert!(parser.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_parse_message_body() {
+        let body = Parser::parse_message_body(
+            r#"
+            option deprecated = true;
+            optional int32 a = 1;
+            oneof o { string b = 2; }
+            reserved 3;
+            reserved "c";
+            extensions 100 to max;
+            message N {}
+            enum E { Z = 0; }
+            extend N { optional int32 x = 100; }
+            "#,
+            Syntax::Proto2,
+        )
+        .unwrap();
+        let m = body.into_message("M".to_owned());
+        assert_eq!("M", m.name);
+        assert_eq!(1, m.options.len());
+        assert_eq!(2, m.fields.len());
+        assert_eq!(vec![3..=3], m.reserved_nums);
+        assert_eq!(vec!["c".to_owned()], m.reserved_names);
+        assert_eq!(vec![100..=MAX_FIELD_NUMBER], m.extension_ranges);
+        assert_eq!(1, m.messages.len());
+        assert_eq!(1, m.enums.len());
+        assert_eq!(1, m.extensions.len());
+
+        let body = Parser::parse_message_body(
+            "option message_set_wire_format = true; extensions 4 to max;",
+            Syntax::Proto2,
+        )
+        .unwrap();
+        assert_eq!(vec![4..=i32::MAX - 1], body.extension_ranges);
+
+        let body = Parser::parse_message_body("", Syntax::Proto3).unwrap();
+        assert!(body.into_message(String::new()).fields.is_empty());
+    }
+
+    #[test]
+    fn test_parse_message_body_syntax() {
+        let body = Parser::parse_message_body("int32 a = 1;", Syntax::Proto3).unwrap();
+        assert_eq!(1, body.fields.len());
+
+        let e = Parser::parse_message_body("int32 a = 1;", Syntax::Proto2).unwrap_err();
+        assert!(matches!(parser_error(&e), ParserError::LabelRequired));
+
+        let e = Parser::parse_message_body("int32 foo_bar = 1;\nint32 fooBar = 2;", Syntax::Proto3)
+            .unwrap_err();
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::JsonNameConflict { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_message_body_error() {
+        let e = Parser::parse_message_body("optional int32 a = 1;\n}", Syntax::Proto2).unwrap_err();
+        assert_eq!((2, 1), (e.line, e.col));
+
+        let e = Parser::parse_message_body("optional int32 a = 1", Syntax::Proto2).unwrap_err();
+        assert_eq!((1, 21), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_invalid_json_name() {
+        for (input, json_name, col) in [
+            (
+                r#"message M { optional int32 a = 1 [json_name = ""]; }"#,
+                "",
+                35,
+            ),
+            (
+                r#"message M { optional int32 a = 1 [json_name = "a\nb"]; }"#,
+                "a\nb",
+                35,
+            ),
+            (
+                r#"message M { optional int32 a = 1 [json_name = "\x01"]; }"#,
+                "\u{1}",
+                35,
+            ),
+            (
+                r#"message M { optional int32 a = 1 [json_name = "\t"]; }"#,
+                "\t",
+                35,
+            ),
+            (
+                r#"message M { optional int32 a = 1 [deprecated = true, json_name = "a\177"]; }"#,
+                "a\u{7f}",
+                54,
+            ),
+            // Extensions and fields of oneofs are checked too
+            (
+                r#"extend M { optional int32 a = 1 [json_name = ""]; }"#,
+                "",
+                34,
+            ),
+            (
+                r#"message M { oneof o { int32 a = 1 [json_name = ""]; } }"#,
+                "",
+                36,
+            ),
+        ] {
+            let e = parse_err(input);
+            assert!(
+                matches!(parser_error(&e), ParserError::InvalidJsonName(n) if n == json_name),
+                "{}",
+                input
+            );
+            assert_eq!((1, col), (e.line, e.col), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_valid_json_name() {
+        let file = parse(
+            r#"message M {
+                optional int32 a = 1 [json_name = "x y"];
+                optional int32 b = 2 [json_name = "é"];
+                optional int32 c_d = 3;
+            }"#,
+        );
+        let json_names: Vec<String> = file.messages[0]
+            .t
+            .regular_fields_including_in_oneofs()
+            .iter()
+            .map(|f| f.t.json_name().unwrap())
+            .collect();
+        assert_eq!(vec!["x y", "\u{e9}", "cD"], json_names);
+    }
+
+    #[test]
+    fn test_required_in_extend() {
+        for input in [
+            "message M { extensions 1 to max; }\nextend M { required int32 a = 1; }",
+            "message M { extensions 1 to max; extend M { required int32 a = 1; } }",
+            "syntax = \"proto3\"; extend M { required int32 a = 1; }",
+        ] {
+            let e = parse_err(input);
+            assert!(
+                matches!(parser_error(&e), ParserError::LabelNotAllowed),
+                "{}",
+                input
+            );
+        }
+        let e = parse_err("message M {}\nextend M { required int32 a = 1; }");
+        assert_eq!((2, 12), (e.line, e.col));
+
+        let file = parse(
+            r#"
+            message M {
+                required int32 a = 1;
+                extensions 100 to max;
             }
+            extend M {
+                optional int32 b = 100;
+                repeated int32 c = 101;
+            }
+            "#,
+        );
+        assert_eq!(
+            Some(Rule::Required),
+            file.messages[0].t.field_by_name("a").unwrap().rule
+        );
+        let rules: Vec<Option<Rule>> = file.extensions.iter().map(|e| e.t.field.t.rule).collect();
+        assert_eq!(vec![Some(Rule::Optional), Some(Rule::Repeated)], rules);
+    }
+
+    fn nested_messages(depth: usize) -> String {
+        "message M {".repeat(depth) + &"}".repeat(depth)
+    }
+
+    fn nested_constant(depth: usize) -> String {
+        "{ a ".repeat(depth - 1) + "{ b: 1 " + &"}".repeat(depth)
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        parse(&nested_messages(MAX_RECURSION_DEPTH as usize));
+        let e = parse_err(&nested_messages(MAX_RECURSION_DEPTH as usize + 1));
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RecursionLimitExceeded
+        ));
+        // At the innermost message
+        assert_eq!(1, e.line);
+        assert!((1101..=1111).contains(&e.col), "{}", e.col);
+
+        Parser::parse_constant(&nested_constant(MAX_RECURSION_DEPTH as usize)).unwrap();
+        let e =
+            Parser::parse_constant(&nested_constant(MAX_RECURSION_DEPTH as usize + 1)).unwrap_err();
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RecursionLimitExceeded
+        ));
+
+        // Text message itself is not a message constant
+        let text = "a ".to_owned() + &nested_constant(MAX_RECURSION_DEPTH as usize);
+        Parser::parse_text_message(&text).unwrap();
+        let text = "a ".to_owned() + &nested_constant(MAX_RECURSION_DEPTH as usize + 1);
+        assert!(Parser::parse_text_message(&text).is_err());
+    }
+
+    #[test]
+    fn test_recursion_limit_combined() {
+        let depth = MAX_RECURSION_DEPTH as usize / 2;
+        let option = |depth| format!("option (x) = {};", nested_constant(depth));
+        let input = "message M {".repeat(depth) + &option(depth) + &"}".repeat(depth);
+        parse(&input);
+
+        let input = "message M {".repeat(depth) + &option(depth + 1) + &"}".repeat(depth);
+        let e = parse_err(&input);
+        assert!(matches!(
+            parser_error(&e),
+            ParserError::RecursionLimitExceeded
+        ));
+
+        // Depth is restored after a nested block
+        let input = nested_messages(MAX_RECURSION_DEPTH as usize).repeat(2);
+        assert_eq!(2, parse(&input).messages.len());
+    }
 
-            if self.tokenizer.next_symbol_if_eq(';')? {
-                continue;
+    fn known_types(types: &[&str]) -> HashSet<ProtobufAbsPath> {
+        types.iter().map(|t| ProtobufAbsPath::from(*t)).collect()
+    }
+
+    #[test]
+    fn test_known_types() {
+        let file = Parser::new(
+            r#"
+            syntax = "proto3";
+            package a.b;
+            message M {
+                Foo foo = 1;
+                c.Bar bar = 2;
+                .x.Baz baz = 3;
+                N n = 4;
+                message N {}
             }
+            extend Foo { M m = 100; }
+            service S { rpc R(Foo) returns (x.Baz); }
+            "#,
+        )
+        .with_known_types(known_types(&[".a.Foo", ".a.b.c.Bar", ".x.Baz"]))
+        .next_proto()
+        .unwrap();
+        let m = &file.messages[0];
+        assert_eq!(&message_or_enum(".a.Foo"), field_type(m, "foo"));
+        assert_eq!(&message_or_enum(".a.b.c.Bar"), field_type(m, "bar"));
+        assert_eq!(&message_or_enum(".x.Baz"), field_type(m, "baz"));
+        assert_eq!(&message_or_enum(".a.b.M.N"), field_type(m, "n"));
+        let extension = &file.extensions[0];
+        assert_eq!(".a.Foo", extension.extendee.to_string());
+        assert_eq!(message_or_enum(".a.b.M"), extension.field.typ);
+        let method = &file.services[0].methods[0];
+        assert_eq!(".a.Foo", method.input_type.to_string());
+        assert_eq!(".x.Baz", method.output_type.to_string());
+    }
 
-            return Err(ParserError::IncorrectInput.into());
+    #[test]
+    fn test_known_types_innermost_scope() {
+        // Like protoc, the innermost scope declaring the name wins
+        let file = Parser::new("package a.b; message M { optional Foo foo = 1; }")
+            .with_known_types(known_types(&[".Foo", ".a.b.Foo", ".a.Foo"]))
+            .next_proto()
+            .unwrap();
+        assert_eq!(
+            &message_or_enum(".a.b.Foo"),
+            field_type(&file.messages[0], "foo")
+        );
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        for (input, name, scope, col) in [
+            (
+                "package p; message M { optional Foo f = 1; }",
+                "Foo",
+                ".p.M",
+                24,
+            ),
+            (
+                "package p; message M { optional .p.Known f = 1; }",
+                ".p.Known",
+                ".p.M",
+                24,
+            ),
+            (
+                "package p; extend Foo { optional int32 x = 1; }",
+                "Foo",
+                ".p",
+                25,
+            ),
+            ("service S { rpc R(Known) returns (Foo); }", "Foo", "", 35),
+            (
+                "message M { message N { optional Foo f = 1; } }",
+                "Foo",
+                ".M.N",
+                25,
+            ),
+        ] {
+            let mut parser = Parser::new(input);
+            let e = next_proto_err(parser.with_known_types(known_types(&[".Known"])));
+            assert!(
+                matches!(
+                    parser_error(&e),
+                    ParserError::UnknownType(n, s)
+                        if n.to_string() == name && s.to_string() == scope
+                ),
+                "{}: {}",
+                input,
+                e
+            );
+            assert_eq!((1, col), (e.line, e.col), "{}", input);
         }
+    }
 
-        Ok(FileDescriptor {
-            imports,
-            package,
-            syntax,
-            messages,
-            enums,
-            extensions,
-            services,
-            options,
-        })
+    #[test]
+    fn test_known_types_send() {
+        let handle = std::thread::spawn(|| {
+            let mut parser = Parser::new("message M { optional Foo f = 1; }");
+            parser.with_known_types(known_types(&[".Foo"]));
+            // Clones share the known types
+            let mut clone = parser.clone();
+            std::thread::spawn(move || clone.next_proto().unwrap())
+                .join()
+                .unwrap()
+        });
+        let file = handle.join().unwrap();
+        assert_eq!(&message_or_enum(".Foo"), field_type(&file.messages[0], "f"));
+    }
+
+    #[test]
+    fn test_many_options_linear() {
+        // Finding spans of option values used to scan the source from its start
+        let fields: String = (1..=50_000)
+            .map(|i| format!("  optional int32 f{} = {} [deprecated = true];\n", i, i))
+            .collect();
+        let input = format!("message M {{\n{}}}\n", fields);
+        let start = std::time::Instant::now();
+        let file = parse(&input);
+        let elapsed = start.elapsed();
+        let last = file.messages[0].t.field_by_name("f50000").unwrap();
+        let span = last.options[0].value_span.unwrap();
+        assert_eq!("true", &input[span.start..span.end]);
+        assert!(elapsed.as_secs() < 10, "{:?}", elapsed);
     }
 }
@@ -0,0 +1,310 @@
+use std::fmt;
+
+use protobuf_support::lexer::loc::Loc;
+
+use crate::case_convert::{camel_case, snake_case};
+use crate::model::{Enumeration, Field, FieldOrOneOf, FieldType, FileDescriptor, Message, WithLoc};
+
+/// Style rule from the protobuf style guide
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum LintRule {
+    /// Message names are `CamelCase`
+    MessageNameCamelCase,
+    /// Field names are `lower_snake_case`
+    FieldNameLowerSnakeCase,
+    /// Enum value names are `UPPER_SNAKE_CASE`
+    EnumValueUpperSnakeCase,
+    /// Enum value names are prefixed with the enum name in `UPPER_SNAKE_CASE`
+    EnumValuePrefix,
+}
+
+impl LintRule {
+    pub const ALL: [LintRule; 4] = [
+        LintRule::MessageNameCamelCase,
+        LintRule::FieldNameLowerSnakeCase,
+        LintRule::EnumValueUpperSnakeCase,
+        LintRule::EnumValuePrefix,
+    ];
+
+    /// Stable rule identifier
+    pub const fn id(&self) -> &'static str {
+        match self {
+            LintRule::MessageNameCamelCase => "message-name-camel-case",
+            LintRule::FieldNameLowerSnakeCase => "field-name-lower-snake-case",
+            LintRule::EnumValueUpperSnakeCase => "enum-value-upper-snake-case",
+            LintRule::EnumValuePrefix => "enum-value-prefix",
+        }
+    }
+}
+
+/// Style violation
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintFinding {
+    pub rule: LintRule,
+    pub message: String,
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.rule.id(), self.message)
+    }
+}
+
+/// Name consists of `A-Z`, `0-9` and `_`, does not start with a digit
+/// and has no `__`. Digits may be followed by letters, e. g. `KIND_2D`.
+fn is_upper_snake_case(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && !name.contains("__")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[derive(Default)]
+struct Linter {
+    findings: Vec<WithLoc<LintFinding>>,
+}
+
+impl Linter {
+    fn report(&mut self, loc: Loc, rule: LintRule, message: String) {
+        self.findings.push(WithLoc {
+            loc,
+            t: LintFinding { rule, message },
+        });
+    }
+
+    fn message_name(&mut self, loc: Loc, name: &str) {
+        let expected = camel_case(name);
+        if expected != name {
+            self.report(
+                loc,
+                LintRule::MessageNameCamelCase,
+                format!("message `{}` should be named `{}`", name, expected),
+            );
+        }
+    }
+
+    fn field(&mut self, field: &WithLoc<Field>) {
+        match &field.typ {
+            // Group field name is derived from the group name
+            FieldType::Group(group) => {
+                self.message_name(field.loc, &group.name);
+                for f in &group.fields {
+                    self.field(f);
+                }
+            }
+            _ => {
+                let expected = snake_case(&field.name);
+                if expected != field.name {
+                    self.report(
+                        field.loc,
+                        LintRule::FieldNameLowerSnakeCase,
+                        format!("field `{}` should be named `{}`", field.name, expected),
+                    );
+                }
+            }
+        }
+    }
+
+    fn enumeration(&mut self, enumeration: &WithLoc<Enumeration>) {
+        let prefix = format!("{}_", snake_case(&enumeration.name).to_ascii_uppercase());
+        for v in &enumeration.values {
+            if !is_upper_snake_case(&v.name) {
+                let expected = snake_case(&v.name).to_ascii_uppercase();
+                self.report(
+                    v.loc,
+                    LintRule::EnumValueUpperSnakeCase,
+                    format!("enum value `{}` should be named `{}`", v.name, expected),
+                );
+            }
+            if !v.name.starts_with(&prefix) {
+                self.report(
+                    v.loc,
+                    LintRule::EnumValuePrefix,
+                    format!(
+                        "enum value `{}` should be prefixed with `{}`",
+                        v.name, prefix
+                    ),
+                );
+            }
+        }
+    }
+
+    fn message(&mut self, message: &WithLoc<Message>) {
+        self.message_name(message.loc, &message.name);
+        for fo in &message.fields {
+            match &fo.t {
+                FieldOrOneOf::Field(f) => self.field(f),
+                FieldOrOneOf::OneOf(o) => {
+                    for f in &o.fields {
+                        self.field(f);
+                    }
+                }
+            }
+        }
+        for e in &message.extensions {
+            self.field(&e.field);
+        }
+        for m in &message.messages {
+            self.message(m);
+        }
+        for e in &message.enums {
+            self.enumeration(e);
+        }
+    }
+}
+
+/// Check a file against naming conventions of the protobuf style guide.
+///
+/// Findings are reported in declaration order within each kind of declaration.
+pub fn check(file: &FileDescriptor) -> Vec<WithLoc<LintFinding>> {
+    let mut linter = Linter::default();
+    for m in &file.messages {
+        linter.message(m);
+    }
+    for e in &file.enums {
+        linter.enumeration(e);
+    }
+    for e in &file.extensions {
+        linter.field(&e.field);
+    }
+    linter.findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn findings(input: &str) -> Vec<(LintRule, String, u32)> {
+        let file = FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e));
+        check(&file)
+            .into_iter()
+            .map(|f| (f.t.rule, f.t.message, f.loc.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_file() {
+        let input = r#"
+            syntax = "proto3";
+            message FooBar {
+                int32 foo_bar = 1;
+                enum Kind {
+                    KIND_UNSPECIFIED = 0;
+                    KIND_A = 1;
+                }
+            }
+            enum TopLevel { TOP_LEVEL_UNKNOWN = 0; }
+        "#;
+        assert_eq!(Vec::<(LintRule, String, u32)>::new(), findings(input));
+    }
+
+    #[test]
+    fn test_message_name_camel_case() {
+        assert_eq!(
+            vec![(
+                LintRule::MessageNameCamelCase,
+                "message `foo_bar` should be named `FooBar`".to_owned(),
+                2
+            )],
+            findings("syntax = \"proto3\";\nmessage foo_bar {}")
+        );
+    }
+
+    #[test]
+    fn test_field_name_lower_snake_case() {
+        assert_eq!(
+            vec![(
+                LintRule::FieldNameLowerSnakeCase,
+                "field `fooBar` should be named `foo_bar`".to_owned(),
+                3
+            )],
+            findings("syntax = \"proto3\";\nmessage M {\n  int32 fooBar = 1;\n}")
+        );
+    }
+
+    #[test]
+    fn test_enum_value_upper_snake_case() {
+        assert_eq!(
+            vec![(
+                LintRule::EnumValueUpperSnakeCase,
+                "enum value `E_Foo` should be named `E_FOO`".to_owned(),
+                1
+            )],
+            findings("enum E { E_Foo = 0; }")
+        );
+    }
+
+    #[test]
+    fn test_enum_value_prefix() {
+        assert_eq!(
+            vec![(
+                LintRule::EnumValuePrefix,
+                "enum value `OTHER` should be prefixed with `MY_ENUM_`".to_owned(),
+                1
+            )],
+            findings("enum MyEnum { MY_ENUM_A = 0; OTHER = 1; }")
+        );
+    }
+
+    #[test]
+    fn test_rule_ids() {
+        let ids: Vec<&str> = LintRule::ALL.iter().map(|r| r.id()).collect();
+        assert_eq!(
+            vec![
+                "message-name-camel-case",
+                "field-name-lower-snake-case",
+                "enum-value-upper-snake-case",
+                "enum-value-prefix"
+            ],
+            ids
+        );
+    }
+
+    #[test]
+    fn test_enum_value_with_digits() {
+        let input = r#"
+            enum Kind {
+                KIND_2D = 0;
+                KIND_3D_MESH = 1;
+                KIND_SHA256SUM = 2;
+                KIND_V2 = 3;
+            }
+        "#;
+        assert_eq!(Vec::<(LintRule, String, u32)>::new(), findings(input));
+    }
+
+    #[test]
+    fn test_enum_value_not_upper_snake_case() {
+        let input = "enum E {\n  E_A = 0;\n  E__B = 1;\n  E_c = 2;\n  E_2d = 3;\n}";
+        let rules_and_lines: Vec<(LintRule, u32)> = findings(input)
+            .into_iter()
+            .map(|(rule, _, line)| (rule, line))
+            .collect();
+        assert_eq!(
+            vec![
+                (LintRule::EnumValueUpperSnakeCase, 3),
+                (LintRule::EnumValueUpperSnakeCase, 4),
+                (LintRule::EnumValueUpperSnakeCase, 5),
+            ],
+            rules_and_lines
+        );
+    }
+
+    #[test]
+    fn test_enum_value_finding_loc() {
+        let file = FileDescriptor::parse("enum E {\n  E_A = 0;\n    Other = 1;\n}").unwrap();
+        let locs: Vec<(LintRule, u32, u32)> = check(&file)
+            .into_iter()
+            .map(|f| (f.t.rule, f.loc.line, f.loc.col))
+            .collect();
+        assert_eq!(
+            vec![
+                (LintRule::EnumValueUpperSnakeCase, 3, 5),
+                (LintRule::EnumValuePrefix, 3, 5)
+            ],
+            locs
+        );
+    }
+}
@@ -1,22 +1,40 @@
 use std::fmt;
 use std::fmt::Write;
+use std::mem;
 
 use std::ops::{Deref, RangeInclusive};
 
-use indexmap::IndexMap;
+use indexmap::{map::Entry, IndexMap};
 use protobuf::reflect::{ReflectValueBox, RuntimeType};
 
-use protobuf_support::lexer::{float::format_protobuf_float, loc::Loc, str_lit::StrLit};
+use protobuf_support::json_name::json_name;
+use protobuf_support::lexer::str_lit::StrLit;
+
+/// 1-based line and column of a declaration.
+///
+/// Constructed with `Loc { line, col }`; locations are ordered
+/// by line, then by column.
+pub use protobuf_support::lexer::loc::Loc;
 
 use crate::{
-    proto_path::ProtoPathBuf, protobuf_abs_path::ProtobufAbsPath, protobuf_ident::ProtobufIdent,
-    protobuf_path::ProtobufPath, Parser, ParserErrorWithLocation, Syntax,
+    convert,
+    float::format_protobuf_float,
+    printer::{self, Printer},
+    proto_path::{ProtoPath, ProtoPathBuf},
+    protobuf_abs_path::ProtobufAbsPath,
+    protobuf_ident::ProtobufIdent,
+    protobuf_path::ProtobufPath,
+    protobuf_rel_path::ProtobufRelPath,
+    str_lit::StrLitEx,
+    utf8, Parser, ParserError, ParserErrorWithLocation, Syntax,
 };
 
 #[derive(thiserror::Error, Debug)]
 enum ModelError {
     #[error("cannot convert value `{1}` to type `{0}`")]
     InconvertibleValue(RuntimeType, ProtobufConstant),
+    #[error("enum `{0}` has no value named `{1}`")]
+    UnknownEnumValue(String, ProtobufPath),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -39,10 +57,65 @@ impl<T> WithLoc<T> {
     }
 }
 
+/// Option statement or field option.
+///
+/// An option set by several statements (e. g. a repeated custom option)
+/// is represented by one `ProtobufOption` per statement, in declaration order.
+/// Use [`merge_repeated_options`] to combine them.
 #[derive(Debug, Clone, PartialEq)]
 pub struct ProtobufOption {
     pub name: ProtobufOptionName,
     pub value: ProtobufConstant,
+    /// Source text of the value, `None` if not parsed from source
+    pub value_span: Option<Span>,
+    /// Location of `option` keyword, or of the name of a field option
+    pub loc: Loc,
+}
+
+/// Byte range of source text, `&source[start..end]`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Combine options set more than once into a single option
+/// with a `ProtobufConstant::Repeated` value.
+///
+/// Options are returned in order of their first occurrence.
+/// Formatting writes a combined option as one statement per value.
+pub fn merge_repeated_options(options: &[ProtobufOption]) -> Vec<ProtobufOption> {
+    let mut r: Vec<ProtobufOption> = Vec::new();
+    for o in options {
+        match r.iter_mut().find(|m| m.name == o.name) {
+            Some(m) => match &mut m.value {
+                ProtobufConstant::Repeated(values) => values.push(o.value.clone()),
+                value => {
+                    let first = mem::replace(value, ProtobufConstant::Repeated(Vec::new()));
+                    *value = ProtobufConstant::Repeated(vec![first, o.value.clone()]);
+                }
+            },
+            None => r.push(o.clone()),
+        }
+    }
+    r
+}
+
+/// Options keyed by name, the last value wins
+fn options_map(options: &[ProtobufOption]) -> IndexMap<String, &ProtobufConstant> {
+    let mut r = IndexMap::new();
+    for o in options {
+        r.insert(o.name.to_string(), &o.value);
+    }
+    r
+}
+
+/// Options contain `message_set_wire_format = true`
+pub(crate) fn is_message_set(options: &[ProtobufOption]) -> bool {
+    options_map(options)
+        .get("message_set_wire_format")
+        .and_then(|v| v.as_bool())
+        == Some(true)
 }
 
 /// Visibility of import statement
@@ -94,20 +167,37 @@ impl fmt::Display for ProtobufConstantMessageFieldName {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Default)]
+/// Message constant, fields are compared in order,
+/// see [`semantically_eq`](ProtobufConstantMessage::semantically_eq)
+#[derive(Debug, Clone, Default)]
 pub struct ProtobufConstantMessage {
     pub(crate) fields: IndexMap<ProtobufConstantMessageFieldName, ProtobufConstant>,
 }
 
+impl PartialEq for ProtobufConstantMessage {
+    fn eq(&self, other: &Self) -> bool {
+        // `IndexMap` equality ignores order
+        self.fields.iter().eq(other.fields.iter())
+    }
+}
+
+/// Value of an option or of a message constant field.
+///
+/// More variants may be added in minor releases.
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum ProtobufConstant {
     U64(u64),
     I64(i64),
     F64(f64), // TODO: eq
+    /// `true` or `false`; other spellings like `True` are `Ident`
     Bool(bool),
     Ident(ProtobufPath),
     String(StrLit),
     Message(ProtobufConstantMessage),
+    /// Values of a message constant field which is set more than once,
+    /// or set with a list (`[a, b]`)
+    Repeated(Vec<ProtobufConstant>),
 }
 
 impl fmt::Display for ProtobufConstant {
@@ -121,6 +211,7 @@ impl fmt::Display for ProtobufConstant {
             ProtobufConstant::String(v) => write!(f, "{}", v),
             // TODO: text format explicitly
             ProtobufConstant::Message(v) => write!(f, "{:?}", v),
+            ProtobufConstant::Repeated(v) => write!(f, "{:?}", v),
         }
     }
 }
@@ -129,7 +220,10 @@ impl ProtobufConstantMessage {
     pub fn format(&self) -> String {
         let mut s = String::new();
         write!(s, "{{").unwrap();
-        for (n, v) in &self.fields {
+        for (i, (n, v)) in self.fields.iter().enumerate() {
+            if i != 0 {
+                write!(s, " ").unwrap();
+            }
             match v {
                 ProtobufConstant::Message(m) => write!(s, "{} {}", n, m.format()).unwrap(),
                 v => write!(s, "{}: {}", n, v.format()).unwrap(),
@@ -138,6 +232,42 @@ impl ProtobufConstantMessage {
         write!(s, "}}").unwrap();
         s
     }
+
+    /// Compare ignoring field order. Values of a repeated field
+    /// are still compared in order.
+    pub fn semantically_eq(&self, other: &ProtobufConstantMessage) -> bool {
+        self.fields.len() == other.fields.len()
+            && self.fields.iter().all(|(n, v)| match other.fields.get(n) {
+                Some(o) => v.semantically_eq(o),
+                None => false,
+            })
+    }
+
+    /// Add a field, collecting values of a field set more than once
+    /// into `ProtobufConstant::Repeated`.
+    pub(crate) fn add_field(
+        &mut self,
+        name: ProtobufConstantMessageFieldName,
+        value: ProtobufConstant,
+    ) {
+        match self.fields.entry(name) {
+            Entry::Vacant(e) => {
+                e.insert(value);
+            }
+            Entry::Occupied(mut e) => {
+                let prev = mem::replace(e.get_mut(), ProtobufConstant::Repeated(Vec::new()));
+                let mut values = match prev {
+                    ProtobufConstant::Repeated(values) => values,
+                    prev => vec![prev],
+                };
+                match value {
+                    ProtobufConstant::Repeated(more) => values.extend(more),
+                    value => values.push(value),
+                }
+                *e.get_mut() = ProtobufConstant::Repeated(values);
+            }
+        }
+    }
 }
 
 impl ProtobufConstant {
@@ -150,6 +280,30 @@ impl ProtobufConstant {
             ProtobufConstant::Ident(ref i) => format!("{}", i),
             ProtobufConstant::String(ref s) => s.quoted(),
             ProtobufConstant::Message(ref s) => s.format(),
+            ProtobufConstant::Repeated(ref v) => {
+                let v: Vec<String> = v.iter().map(|v| v.format()).collect();
+                format!("[{}]", v.join(", "))
+            }
+        }
+    }
+
+    /// Compare ignoring field order of message constants,
+    /// see [`ProtobufConstantMessage::semantically_eq`].
+    pub fn semantically_eq(&self, other: &ProtobufConstant) -> bool {
+        match (self, other) {
+            (ProtobufConstant::Message(a), ProtobufConstant::Message(b)) => a.semantically_eq(b),
+            (ProtobufConstant::Repeated(a), ProtobufConstant::Repeated(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.semantically_eq(b))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Value of a `true` or `false` literal
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            ProtobufConstant::Bool(b) => Some(b),
+            _ => None,
         }
     }
 
@@ -157,13 +311,18 @@ impl ProtobufConstant {
     pub fn as_type(&self, ty: RuntimeType) -> anyhow::Result<ReflectValueBox> {
         match (self, &ty) {
             (ProtobufConstant::Ident(ident), RuntimeType::Enum(e)) => {
-                if let Some(v) = e.value_by_name(&ident.to_string()) {
-                    return Ok(ReflectValueBox::Enum(e.clone(), v.value()));
-                }
+                return match e.value_by_name(&ident.to_string()) {
+                    Some(v) => Ok(ReflectValueBox::Enum(e.clone(), v.value())),
+                    None => Err(ModelError::UnknownEnumValue(
+                        e.full_name().to_owned(),
+                        ident.clone(),
+                    )
+                    .into()),
+                };
             }
             (ProtobufConstant::Bool(b), RuntimeType::Bool) => return Ok(ReflectValueBox::Bool(*b)),
             (ProtobufConstant::String(lit), RuntimeType::String) => {
-                return Ok(ReflectValueBox::String(lit.decode_utf8()?))
+                return Ok(ReflectValueBox::String(lit.decode_proto_utf8()?))
             }
             _ => {}
         }
@@ -256,6 +415,167 @@ pub struct Field {
     pub options: Vec<ProtobufOption>,
 }
 
+/// Value of the `features.field_presence` edition feature
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FieldPresence {
+    Explicit,
+    Implicit,
+    LegacyRequired,
+}
+
+impl FieldPresence {
+    /// `features.field_presence` set in given field, message or file options
+    pub fn from_options(options: &[ProtobufOption]) -> Option<FieldPresence> {
+        options
+            .iter()
+            .rev()
+            .filter(|o| o.name.to_string() == "features.field_presence")
+            .find_map(|o| match &o.value {
+                ProtobufConstant::Ident(ident) => match ident.to_string().as_str() {
+                    "EXPLICIT" => Some(FieldPresence::Explicit),
+                    "IMPLICIT" => Some(FieldPresence::Implicit),
+                    "LEGACY_REQUIRED" => Some(FieldPresence::LegacyRequired),
+                    _ => None,
+                },
+                _ => None,
+            })
+    }
+}
+
+/// Syntax-dependent properties of a field computed with `Field::resolved`,
+/// which needs to know whether a `MessageOrEnum` type is a message or an enum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedField {
+    pub has_presence: bool,
+    pub is_packed: bool,
+    pub json_name: String,
+    pub is_map: bool,
+}
+
+impl Field {
+    /// JSON name of the field, set with `json_name` option
+    /// or derived from the field name.
+    ///
+    /// Fails if the option is empty or contains control characters.
+    pub fn json_name(&self) -> anyhow::Result<String> {
+        let json_name_option = ProtobufOptionName::simple("json_name");
+        match self.options.iter().find(|o| o.name == json_name_option) {
+            Some(ProtobufOption {
+                value: ProtobufConstant::String(s),
+                ..
+            }) => {
+                let json_name = s.decode_proto_utf8()?;
+                if json_name.is_empty() || json_name.chars().any(|c| c.is_control()) {
+                    return Err(ParserError::InvalidJsonName(json_name).into());
+                }
+                Ok(json_name)
+            }
+            Some(o) => {
+                Err(ModelError::InconvertibleValue(RuntimeType::String, o.value.clone()).into())
+            }
+            None => Ok(json_name(&self.name)),
+        }
+    }
+
+    /// Whether the field tracks presence of its value.
+    ///
+    /// Fields of a oneof (`in_oneof`) always have presence,
+    /// repeated and map fields never have it.
+    /// Otherwise `features.field_presence` of the field, or `inherited_features`
+    /// of the enclosing message or file, takes precedence over `syntax` rules.
+    ///
+    /// `is_message` tells whether a `MessageOrEnum` type is a message or an enum,
+    /// message fields always have presence.
+    pub fn has_presence(
+        &self,
+        syntax: Syntax,
+        in_oneof: bool,
+        inherited_features: Option<FieldPresence>,
+        is_message: impl FnOnce(&ProtobufPath) -> bool,
+    ) -> bool {
+        if in_oneof {
+            return true;
+        }
+        if self.rule == Some(Rule::Repeated) || self.typ.map_key().is_some() {
+            return false;
+        }
+        match &self.typ {
+            FieldType::Group(..) => return true,
+            FieldType::MessageOrEnum(path) if is_message(path) => return true,
+            _ => {}
+        }
+        match FieldPresence::from_options(&self.options).or(inherited_features) {
+            Some(FieldPresence::Explicit) | Some(FieldPresence::LegacyRequired) => true,
+            Some(FieldPresence::Implicit) => false,
+            None => match syntax {
+                Syntax::Proto2 => true,
+                Syntax::Proto3 => self.rule == Some(Rule::Optional),
+            },
+        }
+    }
+
+    /// Whether the repeated field is encoded packed, set with `packed` option
+    /// or packed by default in proto3. Only numeric, bool and enum fields are packable.
+    ///
+    /// `is_message` tells whether a `MessageOrEnum` type is a message or an enum.
+    pub fn is_packed(
+        &self,
+        syntax: Syntax,
+        is_message: impl FnOnce(&ProtobufPath) -> bool,
+    ) -> bool {
+        if self.rule != Some(Rule::Repeated) {
+            return false;
+        }
+        let packable = match &self.typ {
+            FieldType::String | FieldType::Bytes => false,
+            FieldType::MessageOrEnum(path) => !is_message(path),
+            typ => typ.scalar_name().is_some(),
+        };
+        let packed_option = ProtobufOptionName::simple("packed");
+        match self.options.iter().rev().find(|o| o.name == packed_option) {
+            Some(o) => o.value.as_bool() == Some(true) && packable,
+            None => packable && syntax == Syntax::Proto3,
+        }
+    }
+
+    /// Whether this is a non-repeated message field of a proto3 file, which
+    /// has presence unlike other proto3 fields without `optional` label.
+    ///
+    /// `is_message` tells whether a `MessageOrEnum` type is a message or an enum.
+    pub fn is_singular_message(
+        &self,
+        syntax: Syntax,
+        is_message: impl FnOnce(&ProtobufPath) -> bool,
+    ) -> bool {
+        if syntax != Syntax::Proto3 || self.rule == Some(Rule::Repeated) {
+            return false;
+        }
+        match &self.typ {
+            FieldType::MessageOrEnum(path) => is_message(path),
+            _ => false,
+        }
+    }
+
+    /// Presence, packed encoding, JSON name and map-ness of the field in one call.
+    ///
+    /// `in_oneof` and `inherited_features` are as in [`Field::has_presence`],
+    /// `is_message` tells whether a `MessageOrEnum` type is a message or an enum.
+    pub fn resolved(
+        &self,
+        syntax: Syntax,
+        in_oneof: bool,
+        inherited_features: Option<FieldPresence>,
+        is_message: impl Fn(&ProtobufPath) -> bool,
+    ) -> anyhow::Result<ResolvedField> {
+        Ok(ResolvedField {
+            has_presence: self.has_presence(syntax, in_oneof, inherited_features, &is_message),
+            is_packed: self.is_packed(syntax, &is_message),
+            json_name: self.json_name()?,
+            is_map: self.typ.map_key().is_some(),
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Group {
     pub name: String,
@@ -284,6 +604,69 @@ pub enum FieldType {
     Group(Group),
 }
 
+impl FieldType {
+    /// Value type of a map, or this type otherwise.
+    ///
+    /// Whether the field is repeated is determined by `Field::rule`.
+    pub fn element_type(&self) -> &FieldType {
+        match self {
+            FieldType::Map(key_value) => &key_value.1,
+            t => t,
+        }
+    }
+
+    /// Key type of a map
+    pub fn map_key(&self) -> Option<&FieldType> {
+        match self {
+            FieldType::Map(key_value) => Some(&key_value.0),
+            _ => None,
+        }
+    }
+
+    /// Keyword of a scalar type, e. g. `sint32`, `None` for other types
+    pub fn scalar_name(&self) -> Option<&'static str> {
+        Some(match self {
+            FieldType::Int32 => "int32",
+            FieldType::Int64 => "int64",
+            FieldType::Uint32 => "uint32",
+            FieldType::Uint64 => "uint64",
+            FieldType::Sint32 => "sint32",
+            FieldType::Sint64 => "sint64",
+            FieldType::Bool => "bool",
+            FieldType::Fixed64 => "fixed64",
+            FieldType::Sfixed64 => "sfixed64",
+            FieldType::Double => "double",
+            FieldType::String => "string",
+            FieldType::Bytes => "bytes",
+            FieldType::Fixed32 => "fixed32",
+            FieldType::Sfixed32 => "sfixed32",
+            FieldType::Float => "float",
+            FieldType::MessageOrEnum(..) | FieldType::Map(..) | FieldType::Group(..) => {
+                return None
+            }
+        })
+    }
+
+    fn collect_type_refs(&self, loc: Loc, r: &mut Vec<WithLoc<ProtobufPath>>) {
+        match self {
+            FieldType::MessageOrEnum(path) => r.push(WithLoc {
+                loc,
+                t: path.clone(),
+            }),
+            FieldType::Map(key_value) => {
+                key_value.0.collect_type_refs(loc, r);
+                key_value.1.collect_type_refs(loc, r);
+            }
+            FieldType::Group(group) => {
+                for f in &group.fields {
+                    f.t.typ.collect_type_refs(f.loc, r);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Message {
     /// Message name
@@ -300,7 +683,7 @@ pub struct Message {
     pub enums: Vec<WithLoc<Enumeration>>,
     /// Non-builtin options
     pub options: Vec<ProtobufOption>,
-    /// Extension field numbers
+    /// Extension field numbers, `max` is `i32::MAX - 1` in a MessageSet
     pub extension_ranges: Vec<RangeInclusive<i32>>,
     /// Extensions
     pub extensions: Vec<WithLoc<Extension>>,
@@ -325,6 +708,37 @@ impl Message {
             .map(|f| &f.t)
     }
 
+    /// Paths of nested messages and enums relative to this message,
+    /// including deeper nested types (e. g. `Inner`, `Inner.Deeper`).
+    pub fn nested_type_names(&self) -> Vec<ProtobufRelPath> {
+        let mut r = Vec::new();
+        for m in &self.messages {
+            let name = ProtobufRelPath::new(m.t.name.clone());
+            let nested: Vec<ProtobufRelPath> =
+                m.t.nested_type_names()
+                    .iter()
+                    .map(|n| name.append(n))
+                    .collect();
+            r.push(name);
+            r.extend(nested);
+        }
+        for e in &self.enums {
+            r.push(ProtobufRelPath::new(e.t.name.clone()));
+        }
+        r
+    }
+
+    /// Message options by name, see [`Enumeration::options_map`]
+    pub fn options_map(&self) -> IndexMap<String, &ProtobufConstant> {
+        options_map(&self.options)
+    }
+
+    /// Message has `option message_set_wire_format = true;`,
+    /// so extension numbers may be up to `i32::MAX - 1`
+    pub fn is_message_set(&self) -> bool {
+        is_message_set(&self.options)
+    }
+
     pub fn _nested_extensions(&self) -> Vec<&Group> {
         self.regular_fields_including_in_oneofs()
             .into_iter()
@@ -335,6 +749,18 @@ impl Message {
             .collect()
     }
 
+    fn collect_type_refs(&self, r: &mut Vec<WithLoc<ProtobufPath>>) {
+        for f in self.regular_fields_including_in_oneofs() {
+            f.t.typ.collect_type_refs(f.loc, r);
+        }
+        for e in &self.extensions {
+            e.t.collect_type_refs(e.loc, r);
+        }
+        for m in &self.messages {
+            m.t.collect_type_refs(r);
+        }
+    }
+
     #[cfg(test)]
     pub fn regular_fields_for_test(&self) -> Vec<&Field> {
         self.fields
@@ -357,6 +783,15 @@ impl Message {
     }
 }
 
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = FormatOptions::default();
+        let mut printer = Printer::new(&options);
+        printer.message(self);
+        f.write_str(&printer.finish())
+    }
+}
+
 /// A protobuf enumeration field
 #[derive(Debug, Clone)]
 pub struct EnumValue {
@@ -366,6 +801,8 @@ pub struct EnumValue {
     pub number: i32,
     /// enum value options
     pub options: Vec<ProtobufOption>,
+    /// enum value location
+    pub loc: Loc,
 }
 
 /// A protobuf enumerator
@@ -383,6 +820,26 @@ pub struct Enumeration {
     pub reserved_names: Vec<String>,
 }
 
+impl Enumeration {
+    /// Enum options keyed by their name as written, e. g. `(my.ext).field`.
+    ///
+    /// If an option is set more than once, the last value is kept
+    /// at the position of the first occurrence. Use [`merge_repeated_options`]
+    /// to keep all values.
+    pub fn options_map(&self) -> IndexMap<String, &ProtobufConstant> {
+        options_map(&self.options)
+    }
+}
+
+impl fmt::Display for Enumeration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = FormatOptions::default();
+        let mut printer = Printer::new(&options);
+        printer.enumeration(self);
+        f.write_str(&printer.finish())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Extension {
     /// Extend this type with field
@@ -391,6 +848,16 @@ pub struct Extension {
     pub field: WithLoc<Field>,
 }
 
+impl Extension {
+    fn collect_type_refs(&self, loc: Loc, r: &mut Vec<WithLoc<ProtobufPath>>) {
+        r.push(WithLoc {
+            loc,
+            t: self.extendee.clone(),
+        });
+        self.field.t.typ.collect_type_refs(self.field.loc, r);
+    }
+}
+
 /// Service method
 #[derive(Debug, Clone)]
 pub struct Method {
@@ -398,8 +865,12 @@ pub struct Method {
     pub name: String,
     /// Input type
     pub input_type: ProtobufPath,
+    /// Location of input type
+    pub input_type_loc: Loc,
     /// Output type
     pub output_type: ProtobufPath,
+    /// Location of output type
+    pub output_type_loc: Loc,
     /// If this method is client streaming
     #[allow(dead_code)] // TODO
     pub client_streaming: bool,
@@ -408,6 +879,38 @@ pub struct Method {
     pub server_streaming: bool,
     /// Method options
     pub options: Vec<ProtobufOption>,
+    /// If the method is terminated with an options block, possibly empty,
+    /// rather than `;`
+    pub has_options_block: bool,
+}
+
+/// Kind of a method by its streaming flags
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum MethodKind {
+    Unary,
+    ClientStreaming,
+    ServerStreaming,
+    BidiStreaming,
+}
+
+impl Method {
+    pub fn kind(&self) -> MethodKind {
+        match (self.client_streaming, self.server_streaming) {
+            (false, false) => MethodKind::Unary,
+            (true, false) => MethodKind::ClientStreaming,
+            (false, true) => MethodKind::ServerStreaming,
+            (true, true) => MethodKind::BidiStreaming,
+        }
+    }
+}
+
+/// Number of methods of a service of each `MethodKind`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MethodSummary {
+    pub unary: usize,
+    pub client_streaming: usize,
+    pub server_streaming: usize,
+    pub bidi_streaming: usize,
 }
 
 /// Service definition
@@ -419,6 +922,33 @@ pub struct Service {
     pub options: Vec<ProtobufOption>,
 }
 
+impl Service {
+    /// Count methods by kind
+    pub fn method_summary(&self) -> MethodSummary {
+        let mut r = MethodSummary::default();
+        for m in &self.methods {
+            match m.kind() {
+                MethodKind::Unary => r.unary += 1,
+                MethodKind::ClientStreaming => r.client_streaming += 1,
+                MethodKind::ServerStreaming => r.server_streaming += 1,
+                MethodKind::BidiStreaming => r.bidi_streaming += 1,
+            }
+        }
+        r
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = FormatOptions::default();
+        let mut printer = Printer::new(&options);
+        printer.service(self);
+        f.write_str(&printer.finish())
+    }
+}
+
+/// A oneof, its comments are found by [`SourceComments`](crate::comments::SourceComments)
+/// at the location of the enclosing `FieldOrOneOf`
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct OneOf {
     /// OneOf name
@@ -435,6 +965,21 @@ pub enum FieldOrOneOf {
     OneOf(OneOf),
 }
 
+/// Options of `.proto` source formatting
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// String inserted once per nesting level, two spaces by default
+    pub indent: String,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent: "  ".to_owned(),
+        }
+    }
+}
+
 /// A File descriptor representing a whole .proto file
 #[derive(Debug, Default, Clone)]
 pub struct FileDescriptor {
@@ -462,10 +1007,996 @@ impl FileDescriptor {
         let mut parser = Parser::new(file.as_ref());
         match parser.next_proto() {
             Ok(r) => Ok(r),
-            Err(error) => {
-                let Loc { line, col } = parser.tokenizer.loc();
-                Err(ParserErrorWithLocation { error, line, col })
+            Err(error) => Err(ParserErrorWithLocation::new(error, &parser.tokenizer)),
+        }
+    }
+
+    /// If the file parses and its references to types declared in the file
+    /// resolve, see [`Parser::resolve_local_names`].
+    ///
+    /// Stops at the first problem without building a located error.
+    pub fn is_well_formed(file: &str) -> bool {
+        Parser::new(file)
+            .resolve_local_names(true)
+            .next_proto()
+            .is_ok()
+    }
+
+    /// Parses a .proto file content which is not necessarily UTF-8,
+    /// e. g. a legacy file with Latin-1 comments.
+    ///
+    /// Invalid UTF-8 is replaced with U+FFFD in comments,
+    /// and is an error elsewhere.
+    pub fn parse_bytes(file: &[u8]) -> Result<Self, ParserErrorWithLocation> {
+        let (file, replaced) = utf8::decode_lossy(file);
+        if let Some(Loc { line, col }) = utf8::replacement_in_str_lit(&file, &replaced) {
+            return Err(ParserErrorWithLocation {
+                error: ParserError::InvalidUtf8.into(),
+                line,
+                col,
+            });
+        }
+        Self::parse(file)
+    }
+
+    fn imports_with_vis(&self, vis: ImportVis) -> Vec<&Import> {
+        self.imports.iter().filter(|i| i.vis == vis).collect()
+    }
+
+    /// Imports declared with `import public`
+    pub fn public_imports(&self) -> Vec<&Import> {
+        self.imports_with_vis(ImportVis::Public)
+    }
+
+    /// Imports declared with `import weak`
+    pub fn weak_imports(&self) -> Vec<&Import> {
+        self.imports_with_vis(ImportVis::Weak)
+    }
+
+    /// Imports declared without `public` or `weak`
+    pub fn default_imports(&self) -> Vec<&Import> {
+        self.imports_with_vis(ImportVis::Default)
+    }
+
+    /// Format as `.proto` source with given options.
+    ///
+    /// `Display` formats with `FormatOptions::default()`.
+    pub fn format_with(&self, options: &FormatOptions) -> String {
+        let mut printer = Printer::new(options);
+        printer.file(self);
+        printer.finish()
+    }
+
+    /// Format as `.proto` source in a canonical form: declarations sorted
+    /// by name, fields and enum values sorted by number, options sorted
+    /// by name.
+    ///
+    /// Files which differ only in declaration order have the same canonical
+    /// form. Since declaration order is changed, parsing the result does not
+    /// reproduce this file.
+    pub fn format_canonical(&self) -> String {
+        let mut file = self.clone();
+        printer::canonical_file(&mut file);
+        file.to_string()
+    }
+
+    /// If the files declare the same things, possibly in different order.
+    ///
+    /// Files are compared in [canonical form](Self::format_canonical), so
+    /// source locations, comments and formatting are ignored, as well as the
+    /// order of imports, declarations, fields, oneofs, enum values, methods,
+    /// reserved and extension ranges, and of options with different names.
+    /// A method with an empty options block `{}` equals one ending with `;`.
+    ///
+    /// Names, numbers, types, rules and option values are compared, and so is
+    /// the first value of each enum, which is the default of proto2 fields.
+    pub fn semantically_equivalent(&self, other: &FileDescriptor) -> bool {
+        self.semantic_form() == other.semantic_form()
+    }
+
+    /// Hash of the file content, equal for files which are
+    /// [semantically equivalent](Self::semantically_equivalent).
+    ///
+    /// The hash is stable across runs and versions of this crate
+    /// as long as the canonical form does not change.
+    pub fn content_hash(&self) -> u64 {
+        // 64-bit FNV-1a
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let (canonical, first_enum_values) = self.semantic_form();
+        for s in [canonical].iter().chain(&first_enum_values) {
+            // Terminate each string so that concatenations differ
+            for b in s.bytes().chain([0]) {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// Canonical form of the file and first values of its enums
+    fn semantic_form(&self) -> (String, Vec<String>) {
+        let mut file = self.clone();
+        for s in &mut file.services {
+            for m in &mut s.t.methods {
+                m.has_options_block = false;
+            }
+        }
+        printer::canonical_file(&mut file);
+        (file.to_string(), first_enum_values(self))
+    }
+
+    /// Rename a message or enum declared in this file and update all
+    /// references to it (and to types nested in it) in field types,
+    /// extendees and method input and output types.
+    ///
+    /// References which cannot be resolved within this file are left unchanged.
+    /// Relative references whose target would be shadowed by the new name
+    /// are made absolute. Returns the number of updated references.
+    pub fn rename_type(
+        &mut self,
+        from: &ProtobufAbsPath,
+        to: &ProtobufIdent,
+    ) -> anyhow::Result<usize> {
+        convert::rename_type(self, from, to)
+    }
+
+    /// All type references as written: field types (including map keys
+    /// and values), extendees, and method input and output types.
+    pub fn referenced_type_names(&self) -> Vec<WithLoc<ProtobufPath>> {
+        let mut r = Vec::new();
+        for m in &self.messages {
+            m.t.collect_type_refs(&mut r);
+        }
+        for e in &self.extensions {
+            e.t.collect_type_refs(e.loc, &mut r);
+        }
+        for s in &self.services {
+            for m in &s.t.methods {
+                r.push(WithLoc {
+                    loc: m.input_type_loc,
+                    t: m.input_type.clone(),
+                });
+                r.push(WithLoc {
+                    loc: m.output_type_loc,
+                    t: m.output_type.clone(),
+                });
+            }
+        }
+        r
+    }
+
+    /// Paths of all imports in declaration order
+    pub fn all_import_paths(&self) -> impl Iterator<Item = &ProtoPath> {
+        self.imports.iter().map(|i| i.path.as_path())
+    }
+}
+
+/// `Enum = VALUE` of the first value of each enum, nested enums are prefixed
+/// with enclosing message names
+fn first_enum_values(file: &FileDescriptor) -> Vec<String> {
+    fn collect(
+        prefix: &str,
+        messages: &[WithLoc<Message>],
+        enums: &[WithLoc<Enumeration>],
+        r: &mut Vec<String>,
+    ) {
+        for e in enums {
+            if let Some(v) = e.t.values.first() {
+                r.push(format!("{}{} = {}", prefix, e.t.name, v.name));
+            }
+        }
+        for m in messages {
+            let prefix = format!("{}{}.", prefix, m.t.name);
+            collect(&prefix, &m.t.messages, &m.t.enums, r);
+        }
+    }
+
+    let mut r = Vec::new();
+    collect("", &file.messages, &file.enums, &mut r);
+    r.sort();
+    r
+}
+
+impl fmt::Display for FileDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format_with(&FormatOptions::default()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str) -> FileDescriptor {
+        FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn test_imports_by_visibility() {
+        let file = parse(
+            r#"
+            import "a.proto";
+            import public "b.proto";
+            import weak "c.proto";
+            import public "d.proto";
+            "#,
+        );
+        let paths = |imports: Vec<&Import>| -> Vec<String> {
+            imports.iter().map(|i| i.path.to_str().to_owned()).collect()
+        };
+        assert_eq!(vec!["b.proto", "d.proto"], paths(file.public_imports()));
+        assert_eq!(vec!["c.proto"], paths(file.weak_imports()));
+        assert_eq!(vec!["a.proto"], paths(file.default_imports()));
+        assert_eq!(
+            vec!["a.proto", "b.proto", "c.proto", "d.proto"],
+            file.all_import_paths()
+                .map(|p| p.to_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_no_imports() {
+        let file = parse("syntax = \"proto3\";");
+        assert!(file.public_imports().is_empty());
+        assert!(file.weak_imports().is_empty());
+        assert!(file.default_imports().is_empty());
+        assert_eq!(0, file.all_import_paths().count());
+    }
+
+    fn names_and_values(options: &[ProtobufOption]) -> Vec<(String, String)> {
+        options
+            .iter()
+            .map(|o| (o.name.to_string(), o.value.format()))
+            .collect()
+    }
+
+    #[test]
+    fn test_repeated_options_retained() {
+        let file = parse(
+            r#"
+            option (x) = { a: 1 };
+            option java_package = "p";
+            option (x) = { a: 2 };
+            "#,
+        );
+        assert_eq!(
+            vec![
+                ("(x)".to_owned(), "{a: 1}".to_owned()),
+                ("java_package".to_owned(), "\"p\"".to_owned()),
+                ("(x)".to_owned(), "{a: 2}".to_owned()),
+            ],
+            names_and_values(&file.options)
+        );
+    }
+
+    #[test]
+    fn test_merge_repeated_options() {
+        let file = parse(
+            r#"
+            option (x) = { a: 1 };
+            option java_package = "p";
+            option (x) = { a: 2 };
+            option (x) = { a: 3 };
+            "#,
+        );
+        assert_eq!(
+            vec![
+                ("(x)".to_owned(), "[{a: 1}, {a: 2}, {a: 3}]".to_owned()),
+                ("java_package".to_owned(), "\"p\"".to_owned()),
+            ],
+            names_and_values(&merge_repeated_options(&file.options))
+        );
+    }
+
+    #[test]
+    fn test_merge_repeated_options_round_trip() {
+        let mut file = parse(
+            r#"
+            option (x) = { a: 1 };
+            option (x) = { a: 2 };
+            message M {
+                optional int32 f = 1 [(y) = 1, (y) = 2, (z) = 3];
+            }
+            "#,
+        );
+        let file_options = file.options.clone();
+        file.options = merge_repeated_options(&file.options);
+        let field = match &mut file.messages[0].t.fields[0].t {
+            FieldOrOneOf::Field(f) => &mut f.t,
+            FieldOrOneOf::OneOf(..) => unreachable!(),
+        };
+        let field_options = field.options.clone();
+        field.options = merge_repeated_options(&field.options);
+
+        let reparsed = parse(&file.to_string());
+        assert_eq!(
+            names_and_values(&file_options),
+            names_and_values(&reparsed.options)
+        );
+        assert_eq!(
+            names_and_values(&field_options),
+            names_and_values(&reparsed.messages[0].regular_fields_for_test()[0].options)
+        );
+    }
+
+    #[test]
+    fn test_nested_type_names() {
+        let file = parse(
+            r#"
+            message M {
+                message Inner {
+                    message Deeper {}
+                    enum DeeperEnum { A = 0; }
+                }
+                message Other {}
+                enum E { B = 0; }
+            }
+            "#,
+        );
+        assert_eq!(
+            vec!["Inner", "Inner.Deeper", "Inner.DeeperEnum", "Other", "E"],
+            file.messages[0]
+                .nested_type_names()
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+        );
+        let leaf = &file.messages[0].messages[1];
+        assert!(leaf.nested_type_names().is_empty());
+    }
+
+    fn constant(input: &str) -> ProtobufConstant {
+        Parser::parse_constant(input).unwrap()
+    }
+
+    #[test]
+    fn test_message_constant_eq_is_order_sensitive() {
+        let a = constant("{a: 1 b: 2}");
+        let b = constant("{b: 2 a: 1}");
+        assert_ne!(a, b);
+        assert!(a.semantically_eq(&b));
+        assert_eq!(a, constant("{a: 1, b: 2}"));
+    }
+
+    #[test]
+    fn test_semantically_eq_nested() {
+        let a = constant("{m {x: 1 y: [A, B]} n: \"s\"}");
+        let b = constant("{n: \"s\" m {y: [A, B] x: 1}}");
+        assert!(a.semantically_eq(&b));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_semantically_eq_repeated_order() {
+        assert!(!constant("{a: 1 a: 2}").semantically_eq(&constant("{a: 2 a: 1}")));
+        assert!(!constant("{a: 1 a: 1}").semantically_eq(&constant("{a: 1}")));
+        assert!(constant("{a: 1 a: 2}").semantically_eq(&constant("{a: [1, 2]}")));
+        assert!(!constant("{a: 1}").semantically_eq(&constant("{a: 1 b: 2}")));
+        assert!(!constant("{a: 1}").semantically_eq(&constant("{b: 1}")));
+    }
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8_in_concatenated_str_lit() {
+        let input = b"option (x) = \"ab\"\n    \"c\xff\";\n";
+        let e = FileDescriptor::parse_bytes(input).unwrap_err();
+        assert_eq!((1, 14), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_element_type_and_map_key() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                map<string, M> map = 1;
+                repeated sint32 numbers = 2;
+                M message = 3;
+            }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let typ = |name: &str| &message.field_by_name(name).unwrap().typ;
+        let m = FieldType::MessageOrEnum(ProtobufPath::new("M".to_owned()));
+
+        assert_eq!(&m, typ("map").element_type());
+        assert_eq!(Some(&FieldType::String), typ("map").map_key());
+
+        assert_eq!(
+            Some(Rule::Repeated),
+            message.field_by_name("numbers").unwrap().rule
+        );
+        assert_eq!(&FieldType::Sint32, typ("numbers").element_type());
+        assert_eq!(None, typ("numbers").map_key());
+
+        assert_eq!(&m, typ("message").element_type());
+        assert_eq!(None, typ("message").map_key());
+    }
+
+    #[test]
+    fn test_referenced_type_names() {
+        let file = parse(
+            r#"syntax = "proto3";
+message Outer {
+  message Inner {
+    map<string, Outer> outers = 1;
+  }
+  Inner inner = 1;
+  map<int32, Inner> inners = 2;
+  oneof o { E e = 3; }
+}
+enum E { Z = 0; }
+service S {
+  rpc Get(Outer) returns (stream Outer.Inner);
+}
+"#,
+        );
+        let refs: Vec<(String, u32, u32)> = file
+            .referenced_type_names()
+            .into_iter()
+            .map(|r| (r.t.to_string(), r.loc.line, r.loc.col))
+            .collect();
+        let expected = [
+            ("Inner", 6, 3),
+            ("Inner", 7, 3),
+            ("E", 8, 13),
+            ("Outer", 4, 5),
+            ("Outer", 12, 11),
+            ("Outer.Inner", 12, 34),
+        ];
+        let expected: Vec<(String, u32, u32)> = expected
+            .iter()
+            .map(|(t, line, col)| (t.to_string(), *line, *col))
+            .collect();
+        assert_eq!(expected, refs);
+    }
+
+    fn is_message(path: &ProtobufPath) -> bool {
+        path.to_string() != "E"
+    }
+
+    #[test]
+    fn test_has_presence() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                int32 implicit = 1;
+                optional int32 optional = 2;
+                int32 explicit = 3 [features.field_presence = EXPLICIT];
+                M message = 4;
+                E enum = 5;
+                repeated M messages = 6;
+                map<int32, M> map = 7;
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let has_presence = |name: &str, inherited: Option<FieldPresence>| {
+            let field = message.field_by_name(name).unwrap();
+            field.has_presence(Syntax::Proto3, false, inherited, is_message)
+        };
+        assert!(!has_presence("implicit", None));
+        assert!(has_presence("optional", None));
+        assert!(has_presence("explicit", None));
+        assert!(has_presence("message", None));
+        assert!(!has_presence("enum", None));
+        assert!(!has_presence("messages", None));
+        assert!(!has_presence("map", None));
+
+        assert!(has_presence("implicit", Some(FieldPresence::Explicit)));
+        assert!(has_presence("enum", Some(FieldPresence::LegacyRequired)));
+        assert!(has_presence("message", Some(FieldPresence::Implicit)));
+    }
+
+    #[test]
+    fn test_has_presence_oneof() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                oneof o {
+                    int32 a = 1;
+                    E e = 2;
+                }
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        for name in ["a", "e"] {
+            let field = message.field_by_name(name).unwrap();
+            assert!(field.has_presence(Syntax::Proto3, true, None, is_message));
+            assert!(field.has_presence(
+                Syntax::Proto3,
+                true,
+                Some(FieldPresence::Implicit),
+                is_message
+            ));
+            assert!(
+                field
+                    .resolved(Syntax::Proto3, true, None, is_message)
+                    .unwrap()
+                    .has_presence
+            );
+        }
+    }
+
+    #[test]
+    fn test_has_presence_implicit() {
+        let file = parse(
+            r#"
+            message M {
+                optional int32 a = 1 [features.field_presence = IMPLICIT];
+                optional int32 b = 2;
+                optional M m = 3 [features.field_presence = IMPLICIT];
             }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let has_presence = |name: &str, inherited: Option<FieldPresence>| {
+            let field = message.field_by_name(name).unwrap();
+            field.has_presence(Syntax::Proto2, false, inherited, is_message)
+        };
+        assert!(!has_presence("a", None));
+        assert!(!has_presence("a", Some(FieldPresence::Explicit)));
+        assert!(has_presence("b", None));
+        assert!(!has_presence("b", Some(FieldPresence::Implicit)));
+        assert!(has_presence("m", None));
+    }
+
+    #[test]
+    fn test_loc_order() {
+        let a = Loc { line: 1, col: 10 };
+        let b = Loc { line: 2, col: 1 };
+        let c = Loc { line: 2, col: 3 };
+        assert!(a < b && b < c);
+        assert_eq!(a, Loc { line: 1, col: 10 });
+
+        let mut locs = vec![c, a, b];
+        locs.sort();
+        assert_eq!(vec![a, b, c], locs);
+    }
+
+    #[test]
+    fn test_expected_loc() {
+        let file = parse("syntax = \"proto3\";\n\nmessage A {}\n  message B {}\n");
+        let locs: Vec<Loc> = file.messages.iter().map(|m| m.loc).collect();
+        assert_eq!(vec![Loc { line: 3, col: 1 }, Loc { line: 4, col: 3 }], locs);
+    }
+
+    #[test]
+    fn test_options_map() {
+        let file = parse(
+            r#"
+            message M {
+                option deprecated = true;
+                option (my.ext).field = 1;
+                option deprecated = false;
+            }
+            enum E {
+                option allow_alias = true;
+                Z = 0;
+            }
+            "#,
+        );
+        let options = file.messages[0].t.options_map();
+        assert_eq!(
+            vec!["deprecated", "(my.ext).field"],
+            options.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(false), options["deprecated"].as_bool());
+        assert_eq!(&ProtobufConstant::U64(1), options["(my.ext).field"]);
+
+        let options = file.enums[0].t.options_map();
+        assert_eq!(Some(true), options["allow_alias"].as_bool());
+        assert_eq!(1, options.len());
+    }
+
+    #[test]
+    fn test_options_map_empty() {
+        let file = parse("message M {}");
+        assert!(file.messages[0].t.options_map().is_empty());
+    }
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8_in_comment() {
+        let input = b"// caf\xe9\nmessage M { /* \xff\xfe */ }\n";
+        let file = FileDescriptor::parse_bytes(input).unwrap();
+        assert_eq!("M", file.messages[0].t.name);
+    }
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8_in_str_lit() {
+        let input = b"syntax = \"proto3\";\noption (x) = \"caf\xe9\";\n";
+        let e = FileDescriptor::parse_bytes(input).unwrap_err();
+        assert!(matches!(
+            e.error.downcast_ref(),
+            Some(ParserError::InvalidUtf8)
+        ));
+        assert_eq!((2, 14), (e.line, e.col));
+    }
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8_in_ident() {
+        assert!(FileDescriptor::parse_bytes(b"message M\xe9 {}").is_err());
+    }
+
+    #[test]
+    fn test_is_well_formed() {
+        assert!(FileDescriptor::is_well_formed(""));
+        assert!(FileDescriptor::is_well_formed(
+            "syntax = \"proto3\"; message A { B b = 1; } message B {}"
+        ));
+        // Types of other files are not checked
+        assert!(FileDescriptor::is_well_formed(
+            "syntax = \"proto3\"; import \"b.proto\"; message A { b.B b = 1; }"
+        ));
+    }
+
+    #[test]
+    fn test_is_not_well_formed() {
+        // Syntax error
+        assert!(!FileDescriptor::is_well_formed("message A {"));
+        // Unresolved local name
+        assert!(!FileDescriptor::is_well_formed(
+            "syntax = \"proto3\"; message A { A.Missing b = 1; }"
+        ));
+        // Conflicting JSON names
+        assert!(!FileDescriptor::is_well_formed(
+            "syntax = \"proto3\"; message A { int32 a_b = 1; int32 aB = 2; }"
+        ));
+    }
+
+    #[test]
+    fn test_scalar_name() {
+        let scalars = [
+            "int32", "int64", "uint32", "uint64", "sint32", "sint64", "fixed32", "fixed64",
+            "sfixed32", "sfixed64", "bool", "string", "bytes", "float", "double",
+        ];
+        for name in scalars {
+            let typ = Parser::parse_field_type(name).unwrap();
+            assert_eq!(Some(name), typ.scalar_name());
+        }
+    }
+
+    #[test]
+    fn test_scalar_name_of_composite_types() {
+        for typ in ["M", ".p.E", "map<string, int32>"] {
+            assert_eq!(None, Parser::parse_field_type(typ).unwrap().scalar_name());
+        }
+        let group = FieldType::Group(Group {
+            name: "G".to_owned(),
+            fields: Vec::new(),
+        });
+        assert_eq!(None, group.scalar_name());
+    }
+
+    #[test]
+    fn test_is_packed() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                repeated int32 ints = 1;
+                repeated E enums = 2;
+                repeated M messages = 3 [packed = true];
+                repeated string strings = 4 [packed = true];
+                repeated int32 unpacked = 5 [packed = false];
+                int32 singular = 6;
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let is_packed = |name: &str, syntax: Syntax| {
+            let field = message.field_by_name(name).unwrap();
+            field.is_packed(syntax, is_message)
+        };
+        assert!(is_packed("ints", Syntax::Proto3));
+        assert!(is_packed("enums", Syntax::Proto3));
+        assert!(!is_packed("messages", Syntax::Proto3));
+        assert!(!is_packed("strings", Syntax::Proto3));
+        assert!(!is_packed("unpacked", Syntax::Proto3));
+        assert!(!is_packed("singular", Syntax::Proto3));
+
+        assert!(!is_packed("ints", Syntax::Proto2));
+        assert!(!is_packed("enums", Syntax::Proto2));
+    }
+
+    #[test]
+    fn test_is_packed_proto2() {
+        let file = parse(
+            r#"
+            message M {
+                repeated sint64 ints = 1 [packed = true];
+                repeated E enums = 2 [packed = true];
+                repeated M messages = 3 [packed = true];
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let is_packed = |name: &str| {
+            let field = message.field_by_name(name).unwrap();
+            field.is_packed(Syntax::Proto2, is_message)
+        };
+        assert!(is_packed("ints"));
+        assert!(is_packed("enums"));
+        assert!(!is_packed("messages"));
+    }
+
+    #[test]
+    fn test_resolved() {
+        let proto2 = parse(
+            r#"
+            message M {
+                required int32 required = 1 [json_name = "req"];
+            }
+            "#,
+        );
+        let field = proto2.messages[0].t.field_by_name("required").unwrap();
+        assert_eq!(
+            ResolvedField {
+                has_presence: true,
+                is_packed: false,
+                json_name: "req".to_owned(),
+                is_map: false,
+            },
+            field
+                .resolved(Syntax::Proto2, false, None, is_message)
+                .unwrap()
+        );
+
+        let proto3 = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                int32 singular_int = 1;
+                M singular_message = 2;
+                repeated fixed32 repeated_scalar = 3;
+                map<string, int32> map = 4;
+            }
+            "#,
+        );
+        let resolved = |name: &str| {
+            let field = proto3.messages[0].t.field_by_name(name).unwrap();
+            field
+                .resolved(Syntax::Proto3, false, None, is_message)
+                .unwrap()
+        };
+        assert_eq!(
+            ResolvedField {
+                has_presence: false,
+                is_packed: false,
+                json_name: "singularInt".to_owned(),
+                is_map: false,
+            },
+            resolved("singular_int")
+        );
+        assert!(resolved("singular_message").has_presence);
+        assert_eq!(
+            ResolvedField {
+                has_presence: false,
+                is_packed: true,
+                json_name: "repeatedScalar".to_owned(),
+                is_map: false,
+            },
+            resolved("repeated_scalar")
+        );
+        assert!(resolved("map").is_map);
+        assert!(!resolved("map").has_presence);
+
+        // Inherited features
+        let field = proto3.messages[0].t.field_by_name("singular_int").unwrap();
+        assert!(
+            field
+                .resolved(
+                    Syntax::Proto3,
+                    false,
+                    Some(FieldPresence::Explicit),
+                    is_message
+                )
+                .unwrap()
+                .has_presence
+        );
+    }
+
+    #[test]
+    fn test_semantically_equivalent() {
+        let a = parse(
+            r#"
+            syntax = "proto3";
+            import "b.proto";
+            import "a.proto";
+            // Comment
+            message M {
+                option deprecated = true;
+                int32 a = 1;
+                string b = 2 [json_name = "x"];
+                reserved 10, 5 to 6;
+            }
+            enum E { Z = 0; A = 1; }
+            service S {
+                rpc A(M) returns (M) {}
+                rpc B(M) returns (M);
+            }
+            "#,
+        );
+        let b = parse(
+            r#"
+            syntax = "proto3";
+            import "a.proto";
+            import "b.proto";
+            service S { rpc B(M) returns (M) {} rpc A(M) returns (M); }
+            enum E { Z = 0; A = 1; }
+            message M {
+                reserved 5 to 6, 10;
+                string b = 2 [json_name = "x"];
+                int32 a = 1;
+                option deprecated = true;
+            }
+            "#,
+        );
+        assert!(a.semantically_equivalent(&b));
+        assert!(b.semantically_equivalent(&a));
+        assert!(a.semantically_equivalent(&a));
+    }
+
+    #[test]
+    fn test_not_semantically_equivalent() {
+        let base = "message M { optional int32 a = 1 [default = 3]; } enum E { Z = 0; A = 1; }";
+        let file = parse(base);
+        for other in [
+            // Enum default changes with the first value
+            "message M { optional int32 a = 1 [default = 3]; } enum E { A = 1; Z = 0; }",
+            "message M { optional int32 a = 2 [default = 3]; } enum E { Z = 0; A = 1; }",
+            "message M { optional int32 a = 1 [default = 4]; } enum E { Z = 0; A = 1; }",
+            "message M { required int32 a = 1 [default = 3]; } enum E { Z = 0; A = 1; }",
+            "message M { optional int64 a = 1 [default = 3]; } enum E { Z = 0; A = 1; }",
+            "message M { optional int32 b = 1 [default = 3]; } enum E { Z = 0; A = 1; }",
+            "package p; message M { optional int32 a = 1 [default = 3]; } enum E { Z = 0; A = 1; }",
+            "message M { optional int32 a = 1 [default = 3]; }",
+        ] {
+            assert!(!file.semantically_equivalent(&parse(other)), "{}", other);
+        }
+    }
+
+    #[test]
+    fn test_semantically_equivalent_nested_enum_default() {
+        let a = parse("message M { enum E { Z = 0; A = 1; } }");
+        let b = parse("message M { enum E { A = 1; Z = 0; } }");
+        assert!(!a.semantically_equivalent(&b));
+        assert_eq!(a.format_canonical(), b.format_canonical());
+
+        let a = parse("message M { enum E { Z = 0; A = 1; B = 2; } }");
+        let b = parse("message M { enum E { Z = 0; B = 2; A = 1; } }");
+        assert!(a.semantically_equivalent(&b));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let a = parse("// a\nmessage M { optional int32 a = 1; optional int32 b = 2; }");
+        let b = parse("message M {\n  optional int32 b = 2;\n  optional int32 a = 1;\n}\n");
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        for other in [
+            "message M { optional int32 a = 1; optional int32 b = 3; }",
+            "message M { optional int32 a = 1; }",
+            "message N { optional int32 a = 1; optional int32 b = 2; }",
+        ] {
+            assert_ne!(a.content_hash(), parse(other).content_hash(), "{}", other);
+        }
+    }
+
+    #[test]
+    fn test_content_hash_enum_default() {
+        let a = parse("enum E { Z = 0; A = 1; }");
+        let b = parse("enum E { A = 1; Z = 0; }");
+        assert_eq!(a.format_canonical(), b.format_canonical());
+        assert_ne!(a.content_hash(), b.content_hash());
+    }
+
+    #[test]
+    fn test_content_hash_stable() {
+        // 64-bit FNV-1a of the canonical form and the first enum values,
+        // each followed by a zero byte
+        let file = parse("");
+        assert_eq!("syntax = \"proto2\";\n", file.format_canonical());
+        assert_eq!(17398821249566752070, file.content_hash());
+
+        let file = parse("enum E { Z = 0; }");
+        assert_eq!(
+            "syntax = \"proto2\";\n\nenum E {\n  Z = 0;\n}\n",
+            file.format_canonical()
+        );
+        assert_eq!(6336477823918457704, file.content_hash());
+    }
+
+    #[test]
+    fn test_method_summary() {
+        let file = parse(
+            r#"
+            service S {
+                rpc A(M) returns (M);
+                rpc B(stream M) returns (M);
+                rpc C(M) returns (stream M);
+                rpc D(stream M) returns (stream M);
+                rpc E(M) returns (M);
+            }
+            service Empty {}
+            "#,
+        );
+        let kinds: Vec<MethodKind> = file.services[0]
+            .t
+            .methods
+            .iter()
+            .map(|m| m.kind())
+            .collect();
+        assert_eq!(
+            vec![
+                MethodKind::Unary,
+                MethodKind::ClientStreaming,
+                MethodKind::ServerStreaming,
+                MethodKind::BidiStreaming,
+                MethodKind::Unary,
+            ],
+            kinds
+        );
+        assert_eq!(
+            MethodSummary {
+                unary: 2,
+                client_streaming: 1,
+                server_streaming: 1,
+                bidi_streaming: 1,
+            },
+            file.services[0].t.method_summary()
+        );
+        assert_eq!(
+            MethodSummary::default(),
+            file.services[1].t.method_summary()
+        );
+    }
+
+    #[test]
+    fn test_is_singular_message() {
+        let file = parse(
+            r#"
+            syntax = "proto3";
+            message M {
+                M message = 1;
+                optional M optional = 2;
+                repeated M messages = 3;
+                E enum = 4;
+                int32 scalar = 5;
+                map<int32, M> map = 6;
+                oneof o { M in_oneof = 7; }
+            }
+            enum E { Z = 0; }
+            "#,
+        );
+        let message = &file.messages[0].t;
+        let is_singular_message = |name: &str, syntax| {
+            let field = message.field_by_name(name).unwrap();
+            field.is_singular_message(syntax, is_message)
+        };
+        assert!(is_singular_message("message", Syntax::Proto3));
+        assert!(is_singular_message("optional", Syntax::Proto3));
+        assert!(is_singular_message("in_oneof", Syntax::Proto3));
+        assert!(!is_singular_message("messages", Syntax::Proto3));
+        assert!(!is_singular_message("enum", Syntax::Proto3));
+        assert!(!is_singular_message("scalar", Syntax::Proto3));
+        assert!(!is_singular_message("map", Syntax::Proto3));
+    }
+
+    #[test]
+    fn test_is_singular_message_proto2() {
+        let file = parse("message M { optional M m = 1; required M r = 2; }");
+        let message = &file.messages[0].t;
+        for name in ["m", "r"] {
+            let field = message.field_by_name(name).unwrap();
+            assert!(!field.is_singular_message(Syntax::Proto2, |_| true));
         }
     }
 }
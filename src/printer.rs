@@ -0,0 +1,605 @@
+use std::ops::RangeInclusive;
+
+use crate::model::{
+    Enumeration, Extension, Field, FieldOrOneOf, FieldType, FileDescriptor, FormatOptions,
+    ImportVis, Message, Method, OneOf, ProtobufConstant, ProtobufOption, Service, WithLoc,
+};
+use crate::{Syntax, MAX_FIELD_NUMBER};
+
+/// Writes model back as `.proto` source.
+pub(crate) struct Printer<'a> {
+    indent: &'a str,
+    level: usize,
+    buf: String,
+}
+
+fn field_type(typ: &FieldType) -> String {
+    match typ {
+        FieldType::Int32 => "int32".to_owned(),
+        FieldType::Int64 => "int64".to_owned(),
+        FieldType::Uint32 => "uint32".to_owned(),
+        FieldType::Uint64 => "uint64".to_owned(),
+        FieldType::Sint32 => "sint32".to_owned(),
+        FieldType::Sint64 => "sint64".to_owned(),
+        FieldType::Bool => "bool".to_owned(),
+        FieldType::Fixed64 => "fixed64".to_owned(),
+        FieldType::Sfixed64 => "sfixed64".to_owned(),
+        FieldType::Double => "double".to_owned(),
+        FieldType::String => "string".to_owned(),
+        FieldType::Bytes => "bytes".to_owned(),
+        FieldType::Fixed32 => "fixed32".to_owned(),
+        FieldType::Sfixed32 => "sfixed32".to_owned(),
+        FieldType::Float => "float".to_owned(),
+        FieldType::MessageOrEnum(path) => path.to_string(),
+        FieldType::Map(key_value) => {
+            format!(
+                "map<{}, {}>",
+                field_type(&key_value.0),
+                field_type(&key_value.1)
+            )
+        }
+        FieldType::Group(group) => format!("group {}", group.name),
+    }
+}
+
+/// Ranges ending at `max` are printed with the `max` keyword.
+fn ranges(ranges: &[RangeInclusive<i32>], max: i32) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|r| match (*r.start(), *r.end()) {
+            (start, end) if start == end => start.to_string(),
+            (start, end) if end == max => format!("{} to max", start),
+            (start, end) => format!("{} to {}", start, end),
+        })
+        .collect();
+    ranges.join(", ")
+}
+
+fn names(names: &[String]) -> String {
+    let names: Vec<String> = names.iter().map(|n| format!("\"{}\"", n)).collect();
+    names.join(", ")
+}
+
+/// `name = value` of an option. An option combined by
+/// `merge_repeated_options` is set once per value.
+fn option_assignments(option: &ProtobufOption) -> Vec<String> {
+    match &option.value {
+        ProtobufConstant::Repeated(values) => values
+            .iter()
+            .map(|v| format!("{} = {}", option.name, v.format()))
+            .collect(),
+        value => vec![format!("{} = {}", option.name, value.format())],
+    }
+}
+
+/// `[a = 1, b = 2]` suffix of fields and enum values, empty if no options
+fn compact_options(options: &[ProtobufOption]) -> String {
+    if options.is_empty() {
+        return String::new();
+    }
+    let options: Vec<String> = options.iter().flat_map(option_assignments).collect();
+    format!(" [{}]", options.join(", "))
+}
+
+impl<'a> Printer<'a> {
+    pub(crate) fn new(options: &'a FormatOptions) -> Printer<'a> {
+        Printer {
+            indent: &options.indent,
+            level: 0,
+            buf: String::new(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.buf
+    }
+
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            for _ in 0..self.level {
+                self.buf.push_str(self.indent);
+            }
+        }
+        self.buf.push_str(line);
+        self.buf.push('\n');
+    }
+
+    fn block(&mut self, header: &str, body: impl FnOnce(&mut Self)) {
+        self.line(&format!("{} {{", header));
+        self.level += 1;
+        body(self);
+        self.level -= 1;
+        self.line("}");
+    }
+
+    fn options(&mut self, options: &[ProtobufOption]) {
+        for o in options {
+            for assignment in option_assignments(o) {
+                self.line(&format!("option {};", assignment));
+            }
+        }
+    }
+
+    fn reserved(&mut self, nums: &[RangeInclusive<i32>], names: &[String]) {
+        if !nums.is_empty() {
+            self.line(&format!("reserved {};", ranges(nums, MAX_FIELD_NUMBER)));
+        }
+        if !names.is_empty() {
+            self.line(&format!("reserved {};", self::names(names)));
+        }
+    }
+
+    fn field(&mut self, field: &Field) {
+        let rule = match field.rule {
+            Some(rule) => format!("{} ", rule.as_str()),
+            None => String::new(),
+        };
+        match &field.typ {
+            FieldType::Group(group) => {
+                // Field name is derived from the group name
+                let header = format!("{}group {} = {}", rule, group.name, field.number);
+                self.block(&header, |p| {
+                    for f in &group.fields {
+                        p.field(f);
+                    }
+                });
+            }
+            typ => self.line(&format!(
+                "{}{} {} = {}{};",
+                rule,
+                field_type(typ),
+                field.name,
+                field.number,
+                compact_options(&field.options)
+            )),
+        }
+    }
+
+    fn oneof(&mut self, oneof: &OneOf) {
+        self.block(&format!("oneof {}", oneof.name), |p| {
+            p.options(&oneof.options);
+            for f in &oneof.fields {
+                p.field(f);
+            }
+        });
+    }
+
+    fn extensions(&mut self, extensions: &[WithLoc<Extension>]) {
+        // Consecutive extensions of the same type share an `extend` block
+        let mut rem = extensions;
+        while let Some(first) = rem.first() {
+            let len = rem
+                .iter()
+                .take_while(|e| e.extendee == first.extendee)
+                .count();
+            self.block(&format!("extend {}", first.extendee), |p| {
+                for e in &rem[..len] {
+                    p.field(&e.field);
+                }
+            });
+            rem = &rem[len..];
+        }
+    }
+
+    pub(crate) fn message(&mut self, message: &Message) {
+        self.block(&format!("message {}", message.name), |p| {
+            p.options(&message.options);
+            for fo in &message.fields {
+                match &fo.t {
+                    FieldOrOneOf::Field(f) => p.field(f),
+                    FieldOrOneOf::OneOf(o) => p.oneof(o),
+                }
+            }
+            p.reserved(&message.reserved_nums, &message.reserved_names);
+            if !message.extension_ranges.is_empty() {
+                // `max` of MessageSet is `i32::MAX - 1`
+                let max = if message.is_message_set() {
+                    i32::MAX - 1
+                } else {
+                    MAX_FIELD_NUMBER
+                };
+                p.line(&format!(
+                    "extensions {};",
+                    ranges(&message.extension_ranges, max)
+                ));
+            }
+            for m in &message.messages {
+                p.message(m);
+            }
+            for e in &message.enums {
+                p.enumeration(e);
+            }
+            p.extensions(&message.extensions);
+        });
+    }
+
+    pub(crate) fn enumeration(&mut self, enumeration: &Enumeration) {
+        self.block(&format!("enum {}", enumeration.name), |p| {
+            p.options(&enumeration.options);
+            for v in &enumeration.values {
+                p.line(&format!(
+                    "{} = {}{};",
+                    v.name,
+                    v.number,
+                    compact_options(&v.options)
+                ));
+            }
+            p.reserved(&enumeration.reserved_nums, &enumeration.reserved_names);
+        });
+    }
+
+    fn method(&mut self, method: &Method) {
+        let stream = |streaming| if streaming { "stream " } else { "" };
+        let header = format!(
+            "rpc {}({}{}) returns ({}{})",
+            method.name,
+            stream(method.client_streaming),
+            method.input_type,
+            stream(method.server_streaming),
+            method.output_type,
+        );
+        if method.options.is_empty() && !method.has_options_block {
+            self.line(&format!("{};", header));
+        } else if method.options.is_empty() {
+            self.line(&format!("{} {{}}", header));
+        } else {
+            self.block(&header, |p| p.options(&method.options));
+        }
+    }
+
+    pub(crate) fn service(&mut self, service: &Service) {
+        self.block(&format!("service {}", service.name), |p| {
+            p.options(&service.options);
+            for m in &service.methods {
+                p.method(m);
+            }
+        });
+    }
+
+    pub(crate) fn file(&mut self, file: &FileDescriptor) {
+        let syntax = match file.syntax {
+            Syntax::Proto2 => "proto2",
+            Syntax::Proto3 => "proto3",
+        };
+        self.line(&format!("syntax = \"{}\";", syntax));
+
+        if !file.package.is_root() {
+            self.line("");
+            self.line(&format!("package {};", file.package.to_root_rel()));
+        }
+
+        if !file.imports.is_empty() {
+            self.line("");
+            for i in &file.imports {
+                let vis = match i.vis {
+                    ImportVis::Default => "",
+                    ImportVis::Public => "public ",
+                    ImportVis::Weak => "weak ",
+                };
+                self.line(&format!("import {}\"{}\";", vis, i.path));
+            }
+        }
+
+        if !file.options.is_empty() {
+            self.line("");
+            self.options(&file.options);
+        }
+
+        for m in &file.messages {
+            self.line("");
+            self.message(m);
+        }
+
+        for e in &file.enums {
+            self.line("");
+            self.enumeration(e);
+        }
+
+        if !file.extensions.is_empty() {
+            self.line("");
+            self.extensions(&file.extensions);
+        }
+
+        for s in &file.services {
+            self.line("");
+            self.service(s);
+        }
+    }
+}
+
+fn sort_options(options: &mut [ProtobufOption]) {
+    options.sort_by_cached_key(|o| o.name.to_string());
+}
+
+fn canonical_field(field: &mut Field) {
+    sort_options(&mut field.options);
+    if let FieldType::Group(group) = &mut field.typ {
+        sort_fields(&mut group.fields);
+    }
+}
+
+fn sort_fields(fields: &mut [WithLoc<Field>]) {
+    fields.sort_by_key(|f| f.t.number);
+    for f in fields {
+        canonical_field(&mut f.t);
+    }
+}
+
+fn sort_ranges(ranges: &mut [RangeInclusive<i32>]) {
+    ranges.sort_by_key(|r| (*r.start(), *r.end()));
+}
+
+fn sort_extensions(extensions: &mut [WithLoc<Extension>]) {
+    extensions.sort_by_cached_key(|e| (e.t.extendee.to_string(), e.t.field.t.number));
+    for e in extensions {
+        canonical_field(&mut e.t.field.t);
+    }
+}
+
+fn canonical_enumeration(enumeration: &mut Enumeration) {
+    sort_options(&mut enumeration.options);
+    enumeration.values.sort_by_key(|v| v.number);
+    for v in &mut enumeration.values {
+        sort_options(&mut v.options);
+    }
+    sort_ranges(&mut enumeration.reserved_nums);
+    enumeration.reserved_names.sort();
+}
+
+fn canonical_message(message: &mut Message) {
+    sort_options(&mut message.options);
+    for fo in &mut message.fields {
+        match &mut fo.t {
+            FieldOrOneOf::Field(f) => canonical_field(&mut f.t),
+            FieldOrOneOf::OneOf(o) => {
+                sort_options(&mut o.options);
+                sort_fields(&mut o.fields);
+            }
+        }
+    }
+    // Oneof is placed by its lowest field number
+    message.fields.sort_by_key(|fo| match &fo.t {
+        FieldOrOneOf::Field(f) => f.t.number,
+        FieldOrOneOf::OneOf(o) => o.fields.first().map_or(i32::MAX, |f| f.t.number),
+    });
+    sort_ranges(&mut message.reserved_nums);
+    message.reserved_names.sort();
+    sort_ranges(&mut message.extension_ranges);
+    message.messages.sort_by(|a, b| a.t.name.cmp(&b.t.name));
+    for m in &mut message.messages {
+        canonical_message(&mut m.t);
+    }
+    message.enums.sort_by(|a, b| a.t.name.cmp(&b.t.name));
+    for e in &mut message.enums {
+        canonical_enumeration(&mut e.t);
+    }
+    sort_extensions(&mut message.extensions);
+}
+
+/// Sort declarations of a file so that files which differ only
+/// in declaration order become equal.
+pub(crate) fn canonical_file(file: &mut FileDescriptor) {
+    file.imports.sort_by_cached_key(|i| i.path.to_string());
+    sort_options(&mut file.options);
+    file.messages.sort_by(|a, b| a.t.name.cmp(&b.t.name));
+    for m in &mut file.messages {
+        canonical_message(&mut m.t);
+    }
+    file.enums.sort_by(|a, b| a.t.name.cmp(&b.t.name));
+    for e in &mut file.enums {
+        canonical_enumeration(&mut e.t);
+    }
+    sort_extensions(&mut file.extensions);
+    file.services.sort_by(|a, b| a.t.name.cmp(&b.t.name));
+    for s in &mut file.services {
+        sort_options(&mut s.t.options);
+        s.t.methods.sort_by(|a, b| a.name.cmp(&b.name));
+        for m in &mut s.t.methods {
+            sort_options(&mut m.options);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{FileDescriptor, FormatOptions};
+
+    fn parse(input: &str) -> FileDescriptor {
+        FileDescriptor::parse(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    const MESSAGE: &str = r#"message M {
+  option deprecated = true;
+  optional int32 a = 1 [default = 3, json_name = "x"];
+  oneof o {
+    string b = 2;
+    bytes c = 3;
+  }
+  repeated group G = 4 {
+    optional int32 d = 5;
+  }
+  map<string, N> m = 6;
+  reserved 7 to 9, 100 to max;
+  reserved "r", "s";
+  extensions 1000 to 2000;
+  message N {
+  }
+  enum E {
+    Z = 0;
+  }
+  extend M {
+    optional N n = 1000;
+  }
+}
+"#;
+
+    #[test]
+    fn test_message_display() {
+        let file = parse(MESSAGE);
+        let printed = file.messages[0].to_string();
+        assert_eq!(MESSAGE, printed);
+        assert_eq!(printed, parse(&printed).messages[0].to_string());
+    }
+
+    #[test]
+    fn test_enumeration_display() {
+        let input = r#"enum E {
+  option allow_alias = true;
+  A = 0;
+  B = 1 [deprecated = true];
+  C = 1;
+  reserved 5, 10 to 20;
+  reserved "D";
+}
+"#;
+        let file = parse(input);
+        let printed = file.enums[0].to_string();
+        assert_eq!(input, printed);
+        assert_eq!(printed, parse(&printed).enums[0].to_string());
+    }
+
+    #[test]
+    fn test_service_display() {
+        let input = r#"service S {
+  option deprecated = true;
+  rpc A(M) returns (M);
+  rpc B(stream .p.M) returns (stream M) {
+    option deprecated = true;
+  }
+  rpc C(M) returns (stream M) {}
+}
+"#;
+        let file = parse(input);
+        let printed = file.services[0].to_string();
+        assert_eq!(input, printed);
+        assert_eq!(printed, parse(&printed).services[0].to_string());
+    }
+
+    #[test]
+    fn test_file_display() {
+        let input = r#"syntax = "proto3";
+
+package p.q;
+
+import public "a.proto";
+import weak "b.proto";
+
+option java_package = "x";
+
+message M {
+  repeated int32 a = 1;
+}
+
+enum E {
+  Z = 0;
+}
+
+extend google.protobuf.MessageOptions {
+  int32 e = 50000;
+}
+
+service S {
+  rpc A(M) returns (M);
+}
+"#;
+        let printed = parse(input).to_string();
+        assert_eq!(input, printed);
+    }
+
+    #[test]
+    fn test_format_with_indent() {
+        let file = parse("message M { message N { optional int32 a = 1; } }");
+        let expected = r#"syntax = "proto2";
+
+message M {
+    message N {
+        optional int32 a = 1;
+    }
+}
+"#;
+        let spaces = FormatOptions {
+            indent: "    ".to_owned(),
+        };
+        assert_eq!(expected, file.format_with(&spaces));
+        let tabs = FormatOptions {
+            indent: "\t".to_owned(),
+        };
+        assert_eq!(expected.replace("    ", "\t"), file.format_with(&tabs));
+        assert_eq!(expected.replace("    ", "  "), file.to_string());
+    }
+
+    #[test]
+    fn test_format_canonical() {
+        let a = parse(
+            r#"
+            import "b.proto";
+            import "a.proto";
+            message B {
+                optional int32 y = 2 [json_name = "y", deprecated = true];
+                optional int32 x = 1;
+                reserved 20, 10;
+            }
+            message A {}
+            enum E { Z = 1; Y = 0; }
+            "#,
+        );
+        let b = parse(
+            r#"
+            import "a.proto";
+            enum E { Y = 0; Z = 1; }
+            message A {}
+            message B {
+                reserved 10, 20;
+                optional int32 x = 1;
+                optional int32 y = 2 [deprecated = true, json_name = "y"];
+            }
+            import "b.proto";
+            "#,
+        );
+        let expected = r#"syntax = "proto2";
+
+import "a.proto";
+import "b.proto";
+
+message A {
+}
+
+message B {
+  optional int32 x = 1;
+  optional int32 y = 2 [deprecated = true, json_name = "y"];
+  reserved 10, 20;
+}
+
+enum E {
+  Y = 0;
+  Z = 1;
+}
+"#;
+        assert_eq!(expected, a.format_canonical());
+        assert_eq!(expected, b.format_canonical());
+        assert_ne!(a.to_string(), b.to_string());
+    }
+
+    #[test]
+    fn test_format_canonical_differs() {
+        let a = parse("message A { optional int32 x = 1; }");
+        let b = parse("message A { optional int32 x = 2; }");
+        assert_ne!(a.format_canonical(), b.format_canonical());
+    }
+
+    #[test]
+    fn test_message_set_extension_ranges_display() {
+        let input = r#"message S {
+  option message_set_wire_format = true;
+  extensions 4 to max, 10 to 536870911;
+}
+"#;
+        let file = parse(input);
+        let printed = file.messages[0].to_string();
+        assert_eq!(input, printed);
+        assert_eq!(
+            file.messages[0].t.extension_ranges,
+            parse(&printed).messages[0].t.extension_ranges
+        );
+    }
+}
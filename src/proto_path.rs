@@ -120,6 +120,49 @@ impl ProtoPath {
             path: self.path.to_owned(),
         }
     }
+
+    /// Last component of the path, e.g. `bar.proto` for `foo/bar.proto`.
+    pub fn file_name(&self) -> &str {
+        match self.path.rfind('/') {
+            Some(slash) => &self.path[slash + 1..],
+            None => &self.path,
+        }
+    }
+
+    /// File name without the extension, e.g. `bar` for `foo/bar.proto`.
+    ///
+    /// Like `std::path`, only the part after the last dot is the extension,
+    /// and a leading dot does not start an extension.
+    pub fn file_stem(&self) -> &str {
+        self.split_extension().0
+    }
+
+    /// Extension of the file name without the dot, e.g. `proto` for `foo/bar.proto`.
+    pub fn extension(&self) -> Option<&str> {
+        self.split_extension().1
+    }
+
+    /// Path with the extension replaced, or removed if `extension` is empty,
+    /// e.g. `foo/bar.pb.rs` for `foo/bar.proto` and `pb.rs`.
+    pub fn with_extension(&self, extension: &str) -> ProtoPathBuf {
+        let file_name = self.file_name();
+        let dir = &self.path[..self.path.len() - file_name.len()];
+        let mut path = format!("{}{}", dir, self.file_stem());
+        if !extension.is_empty() {
+            path.push('.');
+            path.push_str(extension);
+        }
+        debug_assert!(ProtoPath::new(&path).is_ok(), "{:?}", path);
+        ProtoPathBuf { path }
+    }
+
+    fn split_extension(&self) -> (&str, Option<&str>) {
+        let file_name = self.file_name();
+        match file_name.rfind('.') {
+            Some(0) | None => (file_name, None),
+            Some(dot) => (&file_name[..dot], Some(&file_name[dot + 1..])),
+        }
+    }
 }
 
 impl ProtoPathBuf {
@@ -154,3 +197,52 @@ impl ProtoPathBuf {
         Ok(ProtoPathBuf { path: path_str })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(path: &str) -> &ProtoPath {
+        ProtoPath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!("bar.proto", path("foo/bar.proto").file_name());
+        assert_eq!("bar.proto", path("bar.proto").file_name());
+        assert_eq!("bar", path("foo/bar").file_name());
+    }
+
+    #[test]
+    fn test_file_stem_and_extension() {
+        assert_eq!("bar", path("foo/bar.proto").file_stem());
+        assert_eq!(Some("proto"), path("foo/bar.proto").extension());
+
+        assert_eq!("bar", path("foo/bar").file_stem());
+        assert_eq!(None, path("foo/bar").extension());
+
+        assert_eq!("bar.pb", path("foo/bar.pb.proto").file_stem());
+        assert_eq!(Some("proto"), path("foo/bar.pb.proto").extension());
+
+        assert_eq!(".bar", path("foo/.bar").file_stem());
+        assert_eq!(None, path("foo/.bar").extension());
+
+        assert_eq!("bar", path("foo.d/bar").file_stem());
+        assert_eq!(None, path("foo.d/bar").extension());
+    }
+
+    #[test]
+    fn test_with_extension() {
+        assert_eq!(
+            "foo/bar.pb.rs",
+            path("foo/bar.proto").with_extension("pb.rs").to_str()
+        );
+        assert_eq!("foo/bar.rs", path("foo/bar").with_extension("rs").to_str());
+        assert_eq!(
+            "foo/bar.pb.rs",
+            path("foo/bar.pb.proto").with_extension("rs").to_str()
+        );
+        assert_eq!("foo/bar", path("foo/bar.proto").with_extension("").to_str());
+        assert_eq!("bar.rs", path("bar.proto").with_extension("rs").to_str());
+    }
+}
@@ -12,14 +12,17 @@ use crate::protobuf_rel_path::ProtobufRelPath;
 use crate::protobuf_rel_path::ProtobufRelPathRef;
 
 /// Protobuf absolute name (e. g. `.foo.Bar`).
-#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+///
+/// Paths are ordered lexicographically by components: `.` is less than
+/// any identifier character, so this is the order of strings.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 #[doc(hidden)]
 pub struct ProtobufAbsPath {
     pub path: String,
 }
 
 #[doc(hidden)]
-#[derive(Eq, PartialEq, Debug, Hash)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 #[repr(C)]
 pub struct ProtobufAbsPathRef(str);
 
@@ -273,3 +276,46 @@ impl fmt::Display for ProtobufAbsPath {
         write!(f, "{}", ProtobufAbsPathRef::new(&self.0))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn test_ord() {
+        let mut paths: Vec<ProtobufAbsPath> = [".a_c", ".b", ".a.b", "", ".a0", ".a", ".A"]
+            .into_iter()
+            .map(ProtobufAbsPath::from)
+            .collect();
+        paths.sort();
+        let sorted: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        assert_eq!(vec!["", ".A", ".a", ".a.b", ".a0", ".a_c", ".b"], sorted);
+
+        let set: BTreeSet<ProtobufAbsPath> = paths.iter().rev().cloned().collect();
+        assert_eq!(paths, set.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ord_by_components() {
+        // Order of strings is the order of component lists
+        let paths = [".a", ".a.b", ".a.b.c", ".a.bc", ".a_b", ".ab", ".b"];
+        for a in paths {
+            for b in paths {
+                let components = |p: &str| p.split('.').map(|s| s.to_owned()).collect::<Vec<_>>();
+                assert_eq!(
+                    components(a).cmp(&components(b)),
+                    ProtobufAbsPath::from(a).cmp(&ProtobufAbsPath::from(b)),
+                    "{} {}",
+                    a,
+                    b
+                );
+                assert_eq!(
+                    a.cmp(b),
+                    ProtobufAbsPathRef::new(a).cmp(ProtobufAbsPathRef::new(b))
+                );
+            }
+        }
+    }
+}
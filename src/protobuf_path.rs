@@ -1,4 +1,5 @@
 use std::fmt;
+use std::iter;
 
 use crate::protobuf_abs_path::ProtobufAbsPath;
 use crate::protobuf_rel_path::ProtobufRelPath;
@@ -20,6 +21,26 @@ impl ProtobufPath {
         }
     }
 
+    /// Path without leading dot
+    fn without_dot(&self) -> &str {
+        match self {
+            ProtobufPath::Abs(p) => p.as_str().strip_prefix('.').unwrap_or(p.as_str()),
+            ProtobufPath::Rel(p) => p.as_str(),
+        }
+    }
+
+    /// Path components, e. g. `foo`, `Bar` for both `.foo.Bar` and `foo.Bar`.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        iter::once(self.without_dot())
+            .filter(|s| !s.is_empty())
+            .flat_map(|p| p.split('.'))
+    }
+
+    /// Last path component, e. g. `Bar` for `.foo.Bar`. Empty for empty path.
+    pub fn last_segment(&self) -> &str {
+        self.segments().last().unwrap_or("")
+    }
+
     pub fn _resolve(&self, package: &ProtobufAbsPath) -> ProtobufAbsPath {
         match self {
             ProtobufPath::Abs(p) => p.clone(),
@@ -40,3 +61,33 @@ impl fmt::Display for ProtobufPath {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn segments(path: &str) -> Vec<String> {
+        ProtobufPath::new(path)
+            .segments()
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(vec!["foo", "Bar"], segments(".foo.Bar"));
+        assert_eq!(vec!["foo", "Bar"], segments("foo.Bar"));
+        assert_eq!(vec!["Bar"], segments(".Bar"));
+        assert_eq!(vec!["Bar"], segments("Bar"));
+        assert!(segments("").is_empty());
+    }
+
+    #[test]
+    fn test_last_segment() {
+        assert_eq!("Bar", ProtobufPath::new(".foo.Bar").last_segment());
+        assert_eq!("Bar", ProtobufPath::new("foo.Bar").last_segment());
+        assert_eq!("Bar", ProtobufPath::new(".Bar").last_segment());
+        assert_eq!("Bar", ProtobufPath::new("Bar").last_segment());
+        assert_eq!("", ProtobufPath::new("").last_segment());
+    }
+}
@@ -0,0 +1,212 @@
+use protobuf_support::lexer::{
+    lexer_impl::Lexer, loc::Loc, parser_language::ParserLanguage, token::Token,
+};
+
+use crate::model::Span;
+
+/// Byte offset of a location in the source
+pub(crate) fn loc_to_offset(source: &str, loc: Loc) -> Option<usize> {
+    let mut line_start = 0;
+    for _ in 1..loc.line {
+        line_start += source[line_start..].find('\n')? + 1;
+    }
+    let line = &source[line_start..];
+    match line.char_indices().nth(loc.col as usize - 1) {
+        Some((offset, _)) => Some(line_start + offset),
+        None if line.chars().count() == loc.col as usize - 1 => Some(source.len()),
+        None => None,
+    }
+}
+
+/// Byte offsets of line starts, to find offsets of locations
+/// without scanning the source from its start
+#[derive(Debug)]
+pub(crate) struct LineIndex {
+    /// Offset of each line and whether the line is ASCII,
+    /// so that its columns are byte offsets
+    lines: Vec<(usize, bool)>,
+}
+
+impl LineIndex {
+    pub(crate) fn new(source: &str) -> LineIndex {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for line in source.split_inclusive('\n') {
+            lines.push((start, line.is_ascii()));
+            start += line.len();
+        }
+        // Line after the last newline
+        if source.is_empty() || source.ends_with('\n') {
+            lines.push((start, true));
+        }
+        LineIndex { lines }
+    }
+
+    /// Byte offset of a location in the source the index is built for,
+    /// same as `loc_to_offset`
+    pub(crate) fn offset(&self, source: &str, loc: Loc) -> Option<usize> {
+        let index = (loc.line as usize).checked_sub(1)?;
+        let &(start, ascii) = self.lines.get(index)?;
+        let end = self.lines.get(index + 1).map_or(source.len(), |l| l.0);
+        let col = (loc.col as usize).checked_sub(1)?;
+        if ascii {
+            return (col <= end - start).then_some(start + col);
+        }
+        let line = &source[start..end];
+        match line.char_indices().nth(col) {
+            Some((offset, _)) => Some(start + offset),
+            None if line.chars().count() == col => Some(end),
+            None => None,
+        }
+    }
+}
+
+/// Length of the quoted string literal at the start of `source`
+fn quoted_len(source: &str) -> usize {
+    let mut chars = source.char_indices();
+    let quote = chars.next().map(|(_, c)| c);
+    let mut escaped = false;
+    for (i, c) in chars {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if Some(c) == quote {
+            return i + 1;
+        }
+    }
+    source.len()
+}
+
+/// Byte offset of the end of the token starting at `start`,
+/// `next` is the offset of the lexer after reading the token,
+/// i. e. after the whitespace and comments following it
+pub(crate) fn token_end(source: &str, start: usize, next: usize, token: &Token) -> usize {
+    match token {
+        Token::Symbol(c) => start + c.len_utf8(),
+        // Adjacent string literals are read as a single token,
+        // which ends with the closing quote of the last literal
+        Token::StrLit(..) => {
+            let mut end = start;
+            loop {
+                end += quoted_len(&source[end..next]);
+                let gap = &source[end..next];
+                let mut lexer = Lexer::new(gap, ParserLanguage::Proto);
+                if lexer.skip_ws().is_err() || lexer.eof() {
+                    return end;
+                }
+                match loc_to_offset(gap, lexer.loc) {
+                    Some(offset) => end += offset,
+                    None => return end,
+                }
+            }
+        }
+        _ => {
+            start
+                + source[start..next]
+                    .find(|c: char| c.is_whitespace() || c == '/')
+                    .unwrap_or(next - start)
+        }
+    }
+}
+
+/// Span of the tokens starting at `start`, which are followed by a token at `stop`
+pub(crate) fn tokens_span(
+    source: &str,
+    line_index: &LineIndex,
+    start: Loc,
+    stop: Loc,
+) -> Option<Span> {
+    let start = line_index.offset(source, start)?;
+    let stop = line_index.offset(source, stop)?;
+    let tokens = source.get(start..stop)?;
+
+    let mut lexer = Lexer::new(tokens, ParserLanguage::Proto);
+    let mut last = None;
+    while let Some(token) = lexer.next_token().ok()? {
+        last = Some(token);
+    }
+    let last = last?;
+    let last_offset = loc_to_offset(tokens, last.loc)?;
+    let end = start + token_end(tokens, last_offset, tokens.len(), &last.token);
+
+    Some(Span { start, end })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Text of the span of tokens before `;` on the first line
+    fn span_text(source: &str) -> &str {
+        let col = source[..source.find(';').unwrap()].chars().count() as u32 + 1;
+        let line_index = LineIndex::new(source);
+        let span = tokens_span(
+            source,
+            &line_index,
+            Loc { line: 1, col: 1 },
+            Loc { line: 1, col },
+        )
+        .unwrap();
+        &source[span.start..span.end]
+    }
+
+    #[test]
+    fn test_str_lit() {
+        assert_eq!(span_text("\"ab\";"), "\"ab\"");
+        assert_eq!(span_text("'a\\'b' ;"), "'a\\'b'");
+        assert_eq!(span_text("\"é\" /* c */ ;"), "\"é\"");
+    }
+
+    #[test]
+    fn test_concatenated_str_lit() {
+        assert_eq!(span_text("\"ab\" \"cd\" ;"), "\"ab\" \"cd\"");
+        assert_eq!(
+            span_text("\"a\\\"b\" /* \"x\" */ 'c' /* d */ ;"),
+            "\"a\\\"b\" /* \"x\" */ 'c'"
+        );
+    }
+
+    #[test]
+    fn test_other_tokens() {
+        assert_eq!(span_text("1.5e-3 /* x */;"), "1.5e-3");
+        assert_eq!(span_text("-inf;"), "-inf");
+        assert_eq!(span_text("foo.bar ;"), "foo.bar");
+        assert_eq!(span_text("{ a: 1 };"), "{ a: 1 }");
+    }
+
+    #[test]
+    fn test_line_index() {
+        for source in [
+            "",
+            "a",
+            "\n",
+            "ab\ncd",
+            "ab\ncd\n",
+            "é\nxé\r\n\n€y",
+            "a\n\né",
+        ] {
+            let line_index = LineIndex::new(source);
+            let mut loc = Loc { line: 1, col: 1 };
+            for (offset, c) in source.char_indices().chain([(source.len(), ' ')]) {
+                assert_eq!(Some(offset), loc_to_offset(source, loc));
+                assert_eq!(Some(offset), line_index.offset(source, loc), "{:?}", source);
+                if c == '\n' {
+                    loc = Loc {
+                        line: loc.line + 1,
+                        col: 1,
+                    };
+                } else {
+                    loc.col += 1;
+                }
+            }
+            // Past the end
+            assert_eq!(None, line_index.offset(source, loc));
+            let next_line = Loc {
+                line: loc.line + 1,
+                col: 1,
+            };
+            assert_eq!(None, line_index.offset(source, next_line));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use protobuf_support::lexer::str_lit::{StrLit, StrLitDecodeError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StrLitError {
+    #[error(transparent)]
+    Decode(#[from] StrLitDecodeError),
+    /// Escape not defined by the spec, e. g. `\q`;
+    /// `offset` is the byte offset of `\` in the escaped literal
+    #[error("unknown escape sequence `\\{escape}` at offset {offset} of string literal")]
+    UnknownEscape { escape: char, offset: usize },
+}
+
+/// Decoding of string literals with `.proto` escape rules.
+///
+/// Unlike `StrLit::decode_utf8` and `StrLit::decode_bytes`, this decodes
+/// `\u` and `\U` escapes, `\X` and single digit hex escapes, and optionally
+/// rejects unknown escapes, which those pass through.
+pub trait StrLitEx {
+    /// Decode with [`decode_lenient`](StrLitEx::decode_lenient),
+    /// may fail if not valid UTF8
+    fn decode_proto_utf8(&self) -> Result<String, StrLitError>;
+
+    /// Decode passing unknown escapes through, so `\q` is `q`, like protoc does
+    fn decode_lenient(&self) -> Result<Vec<u8>, StrLitError>;
+
+    /// Decode failing with `StrLitError::UnknownEscape` on unknown escapes
+    fn decode_strict(&self) -> Result<Vec<u8>, StrLitError>;
+}
+
+impl StrLitEx for StrLit {
+    fn decode_proto_utf8(&self) -> Result<String, StrLitError> {
+        Ok(String::from_utf8(self.decode_lenient()?).map_err(StrLitDecodeError::from)?)
+    }
+
+    fn decode_lenient(&self) -> Result<Vec<u8>, StrLitError> {
+        decode(&self.escaped, false)
+    }
+
+    fn decode_strict(&self) -> Result<Vec<u8>, StrLitError> {
+        decode(&self.escaped, true)
+    }
+}
+
+fn next_digits(
+    chars: &mut Peekable<CharIndices>,
+    radix: u32,
+    min: usize,
+    max: usize,
+) -> Result<u32, StrLitDecodeError> {
+    let mut value = 0;
+    let mut count = 0;
+    while count < max {
+        match chars.peek().and_then(|(_, c)| c.to_digit(radix)) {
+            Some(d) => {
+                value = value * radix + d;
+                chars.next();
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    if count < min {
+        return Err(StrLitDecodeError::OtherError);
+    }
+    Ok(value)
+}
+
+fn push_char(r: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    r.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+fn push_code_point(r: &mut Vec<u8>, code: u32) -> Result<(), StrLitDecodeError> {
+    match char::from_u32(code) {
+        Some(c) => {
+            push_char(r, c);
+            Ok(())
+        }
+        None => Err(StrLitDecodeError::OtherError),
+    }
+}
+
+// charValue = hexEscape | octEscape | charEscape | unicodeEscape | /[^\0\n\\]/
+// hexEscape = '\' ( "x" | "X" ) hexDigit [ hexDigit ]
+// octEscape = '\' octalDigit [ octalDigit [ octalDigit ] ]
+// charEscape = '\' ( "a" | "b" | "f" | "n" | "r" | "t" | "v" | '\' | "?" | "'" | '"' )
+// unicodeEscape = '\' "u" hexDigit{4} | '\' "U" hexDigit{8}
+fn decode(escaped: &str, strict: bool) -> Result<Vec<u8>, StrLitError> {
+    let mut r = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            push_char(&mut r, c);
+            continue;
+        }
+        match chars.next().ok_or(StrLitDecodeError::OtherError)?.1 {
+            'a' => r.push(b'\x07'),
+            'b' => r.push(b'\x08'),
+            'f' => r.push(b'\x0c'),
+            'n' => r.push(b'\n'),
+            'r' => r.push(b'\r'),
+            't' => r.push(b'\t'),
+            'v' => r.push(b'\x0b'),
+            c @ ('\\' | '?' | '\'' | '"') => r.push(c as u8),
+            'x' | 'X' => r.push(next_digits(&mut chars, 16, 1, 2)? as u8),
+            c @ '0'..='7' => {
+                let mut value = c as u32 - '0' as u32;
+                for _ in 0..2 {
+                    match chars.peek().and_then(|(_, c)| c.to_digit(8)) {
+                        Some(d) => {
+                            value = (value << 3) + d;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                r.push(value as u8);
+            }
+            'u' => push_code_point(&mut r, next_digits(&mut chars, 16, 4, 4)?)?,
+            'U' => push_code_point(&mut r, next_digits(&mut chars, 16, 8, 8)?)?,
+            // https://github.com/google/protobuf/issues/4562
+            c if strict => return Err(StrLitError::UnknownEscape { escape: c, offset }),
+            c => push_char(&mut r, c),
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(escaped: &str) -> Vec<u8> {
+        StrLit {
+            escaped: escaped.to_owned(),
+        }
+        .decode_strict()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_char_escapes() {
+        assert_eq!(b"\x07", &decode("\\a")[..]);
+        assert_eq!(b"\x0b", &decode("\\v")[..]);
+        assert_eq!(b"?", &decode("\\?")[..]);
+        assert_eq!(b"'", &decode("\\'")[..]);
+        assert_eq!(
+            b"\"\\\x08\x0c\n\r\t",
+            &decode("\\\"\\\\\\b\\f\\n\\r\\t")[..]
+        );
+    }
+
+    #[test]
+    fn test_numeric_escapes() {
+        assert_eq!(b"\x01\x7f", &decode("\\1\\177")[..]);
+        assert_eq!(b"\x0f\xab", &decode("\\xf\\XaB")[..]);
+        assert_eq!(b"\x0012", &decode("\\00012")[..]);
+        assert_eq!(
+            "\u{e9}\u{1f600}".as_bytes(),
+            &decode("\\u00e9\\U0001f600")[..]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_escapes() {
+        for escaped in ["\\", "\\x", "\\u12", "\\U0001f60", "\\ud800"] {
+            let lit = StrLit {
+                escaped: escaped.to_owned(),
+            };
+            assert!(lit.decode_lenient().is_err(), "{}", escaped);
+        }
+    }
+
+    #[test]
+    fn test_decode_proto_utf8() {
+        let lit = StrLit {
+            escaped: "\\a\\u00e9".to_owned(),
+        };
+        assert_eq!("\x07\u{e9}", lit.decode_proto_utf8().unwrap());
+        let lit = StrLit {
+            escaped: "\\xff".to_owned(),
+        };
+        assert!(lit.decode_proto_utf8().is_err());
+    }
+
+    #[test]
+    fn test_unknown_escape_strict() {
+        let lit = StrLit {
+            escaped: "ab\\qc".to_owned(),
+        };
+        match lit.decode_strict() {
+            Err(StrLitError::UnknownEscape { escape, offset }) => {
+                assert_eq!(('q', 2), (escape, offset))
+            }
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_unknown_escape_lenient() {
+        let lit = StrLit {
+            escaped: "ab\\qc\\n".to_owned(),
+        };
+        assert_eq!(b"abqc\n", &lit.decode_lenient().unwrap()[..]);
+        assert_eq!("abqc\n", lit.decode_proto_utf8().unwrap());
+    }
+}
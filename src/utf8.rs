@@ -0,0 +1,41 @@
+use protobuf_support::lexer::{
+    lexer_impl::Lexer, loc::Loc, parser_language::ParserLanguage, token::Token,
+};
+
+use crate::span::{token_end, LineIndex};
+
+/// Decode source replacing invalid UTF-8 sequences with U+FFFD.
+///
+/// Also returns byte offsets of the replacements in the decoded string.
+pub(crate) fn decode_lossy(bytes: &[u8]) -> (String, Vec<usize>) {
+    let mut decoded = String::with_capacity(bytes.len());
+    let mut replaced = Vec::new();
+    for chunk in bytes.utf8_chunks() {
+        decoded.push_str(chunk.valid());
+        if !chunk.invalid().is_empty() {
+            replaced.push(decoded.len());
+            decoded.push(char::REPLACEMENT_CHARACTER);
+        }
+    }
+    (decoded, replaced)
+}
+
+/// Location of the first string literal containing a replacement.
+///
+/// Replacements outside of comments and string literals are not checked,
+/// because they are rejected by the lexer.
+pub(crate) fn replacement_in_str_lit(source: &str, replaced: &[usize]) -> Option<Loc> {
+    let line_index = LineIndex::new(source);
+    let mut lexer = Lexer::new(source, ParserLanguage::Proto);
+    while let Some(token) = lexer.next_token().ok()? {
+        if let Token::StrLit(..) = &token.token {
+            let start = line_index.offset(source, token.loc)?;
+            let next = line_index.offset(source, lexer.loc)?;
+            let end = token_end(source, start, next, &token.token);
+            if replaced.iter().any(|r| (start..end).contains(r)) {
+                return Some(token.loc);
+            }
+        }
+    }
+    None
+}